@@ -1,19 +1,28 @@
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::future::Future;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
 
-use nekoton::core::keystore::KeyStore;
-use tokio::sync::Mutex;
+use serde::Serialize;
 use tokio::task::JoinHandle;
 
+use crate::ffi::{AsyncReply, ReplyErrorCode, SendPort};
 use crate::get_runtime;
-use crate::global::RUNTIME_;
+use crate::global::SUBSCRIPTIONS;
+use crate::handles::Handle;
+use crate::panic::catch_panic_async;
+use crate::wrappers::storage::KeyStoreWrapper;
+use crate::wrappers::RetryPolicy;
 use crate::{ExitCode, GqlTransport, TonWalletSubscription};
 
 #[derive(Clone)]
 pub struct Context {
     pub wallet_state: Arc<TonWalletSubscription>,
+    /// Handle of `wallet_state` in [`SUBSCRIPTIONS`]
+    pub subscription: Handle,
     pub transport: Arc<GqlTransport>,
-    pub keystore: Arc<Mutex<KeyStore>>,
+    pub keystore: KeyStoreWrapper,
+    pub retry_policy: Arc<RwLock<RetryPolicy>>,
     pub manager: Arc<TaskManager>,
 }
 
@@ -21,52 +30,231 @@ impl Context {
     pub fn new(
         wallet_state: TonWalletSubscription,
         transport: Arc<GqlTransport>,
-        keystore: KeyStore,
+        keystore: KeyStoreWrapper,
         manager: TaskManager,
     ) -> Self {
+        let wallet_state = Arc::new(wallet_state);
         Self {
-            wallet_state: Arc::new(wallet_state),
+            subscription: SUBSCRIPTIONS.insert_arc(wallet_state.clone()),
+            wallet_state,
             transport,
-            keystore: Arc::new(Mutex::new(keystore)),
+            keystore,
+            retry_policy: Default::default(),
             manager: Arc::new(manager),
         }
     }
 
-    pub fn spawn<F>(&self, future: F) -> ExitCode
-    where
-        F: Future + Send + 'static,
-        F: Future<Output = ()> + Send + 'static,
-    {
-        let e = get_runtime!().handle();
-        let h = e.spawn(future);
-        e.block_on(self.manager.track(h));
-        ExitCode::Ok
+    pub fn retry_policy(&self) -> RetryPolicy {
+        match self.retry_policy.read() {
+            Ok(policy) => policy.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        match self.retry_policy.write() {
+            Ok(mut current) => *current = policy,
+            Err(e) => *e.into_inner() = policy,
+        }
     }
 }
 
+/// Running tasks by operation id. Tasks are aborted once the last clone of the manager is dropped
 #[derive(Clone, Default)]
 pub struct TaskManager {
-    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    inner: Arc<Tasks>,
 }
 
-impl Drop for TaskManager {
+#[derive(Default)]
+struct Tasks {
+    tasks: Mutex<HashMap<u64, Task>>,
+}
+
+struct Task {
+    name: &'static str,
+    handle: JoinHandle<()>,
+    /// Answer port of the operation, notified on cancellation
+    port: Option<SendPort>,
+}
+
+impl Tasks {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Task>> {
+        match self.tasks.lock() {
+            Ok(tasks) => tasks,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    /// Removes task, returning whether it was still tracked
+    fn finish(tasks: &Weak<Tasks>, id: u64) -> bool {
+        tasks
+            .upgrade()
+            .map_or(false, |tasks| tasks.lock().remove(&id).is_some())
+    }
+}
+
+impl Drop for Tasks {
     fn drop(&mut self) {
-        let e = RUNTIME_
-            .as_ref()
-            .expect("Drop can't be called, when no features are spawned")
-            .handle();
-        let tasks = self.tasks.clone();
-        e.block_on(async move {
-            let tasks = tasks.lock().await;
-            for task in tasks.iter() {
-                task.abort();
-            }
-        });
+        for (id, task) in self.lock().drain() {
+            log::debug!("Aborting task {} `{}`", id, task.name);
+            task.handle.abort();
+        }
     }
 }
 
 impl TaskManager {
-    pub async fn track(&self, task: JoinHandle<()>) {
-        self.tasks.lock().await.push(task)
+    /// Spawns background task with operation `id`, which is forgotten once finished
+    pub fn spawn<F>(&self, name: &'static str, id: u64, future: F) -> ExitCode
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let tasks = Arc::downgrade(&self.inner);
+        self.track(name, id, None, async move {
+            future.await;
+            Tasks::finish(&tasks, id);
+        })
+    }
+
+    /// Spawns asynchronous request `id`, posting [`AsyncReply`] with its result to `port`.
+    /// Nothing is posted, if the request is cancelled meanwhile
+    pub fn spawn_request<F, T, E>(
+        &self,
+        name: &'static str,
+        id: u64,
+        port: SendPort,
+        future: F,
+    ) -> ExitCode
+    where
+        F: Future<Output = Result<T, E>> + Send + 'static,
+        T: Serialize + Send + 'static,
+        E: ReplyErrorCode + Display + Send + 'static,
+    {
+        let tasks = Arc::downgrade(&self.inner);
+        self.track(name, id, Some(port), async move {
+            let result = catch_panic_async(future).await;
+            if Tasks::finish(&tasks, id) {
+                AsyncReply::new(id, result).post(&port);
+            }
+        })
+    }
+
+    /// Aborts task `id`, posting cancelled [`AsyncReply`] to its port.
+    /// Returns `false` if there is no such task or it's already finished
+    pub fn cancel(&self, id: u64) -> bool {
+        let task = match self.inner.lock().remove(&id) {
+            Some(task) => task,
+            None => return false,
+        };
+        log::debug!("Cancelling task {} `{}`", id, task.name);
+        task.handle.abort();
+        if let Some(port) = task.port {
+            AsyncReply::<()>::cancelled(id).post(&port);
+        }
+        true
+    }
+
+    fn track<F>(&self, name: &'static str, id: u64, port: Option<SendPort>, future: F) -> ExitCode
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let runtime = get_runtime!();
+        // Task can't remove itself before it's inserted, while the lock is held
+        let mut tasks = self.inner.lock();
+        let handle = runtime.spawn(future);
+        tasks.insert(id, Task { name, handle, port });
+        ExitCode::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::ffi::test_port::{listen, posted};
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "condition is not met in time");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn tracked(manager: &TaskManager) -> usize {
+        manager.inner.lock().len()
+    }
+
+    #[test]
+    fn finished_task_is_forgotten() {
+        let manager = TaskManager::default();
+        manager.spawn("test", 1, async {});
+        wait_until(|| tracked(&manager) == 0);
+        assert!(!manager.cancel(1));
+    }
+
+    #[test]
+    fn cancelled_task_is_aborted() {
+        let manager = TaskManager::default();
+        let guard = Arc::new(());
+        let task_guard = guard.clone();
+        manager.spawn("test", 1, async move {
+            let _guard = task_guard;
+            futures::future::pending::<()>().await
+        });
+
+        assert!(manager.cancel(1));
+        assert!(!manager.cancel(1));
+        assert_eq!(tracked(&manager), 0);
+        wait_until(|| Arc::strong_count(&guard) == 1);
+    }
+
+    #[test]
+    fn unknown_task_is_not_cancelled() {
+        assert!(!TaskManager::default().cancel(1));
+    }
+
+    #[test]
+    fn dropped_manager_aborts_tasks() {
+        let manager = TaskManager::default();
+        let guard = Arc::new(());
+        let task_guard = guard.clone();
+        manager.spawn("test", 1, async move {
+            let _guard = task_guard;
+            futures::future::pending::<()>().await
+        });
+
+        drop(manager);
+        wait_until(|| Arc::strong_count(&guard) == 1);
+    }
+
+    #[test]
+    fn finished_request_posts_reply() {
+        let manager = TaskManager::default();
+        let port = listen(101);
+        manager.spawn_request("test", 7, port, async { Ok::<_, anyhow::Error>(5) });
+
+        wait_until(|| !posted(101).is_empty());
+        assert_eq!(
+            posted(101),
+            vec![r#"{"request_id":7,"status":"ok","payload":5}"#.to_string()]
+        );
+        assert_eq!(tracked(&manager), 0);
+    }
+
+    #[test]
+    fn cancelled_request_posts_only_cancellation() {
+        let manager = TaskManager::default();
+        let port = listen(102);
+        manager.spawn_request("test", 8, port, async {
+            futures::future::pending::<Result<(), anyhow::Error>>().await
+        });
+
+        assert!(manager.cancel(8));
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            posted(102),
+            vec![r#"{"request_id":8,"status":"cancelled"}"#.to_string()]
+        );
     }
 }
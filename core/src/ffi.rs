@@ -1,13 +1,137 @@
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use std::ffi::CString;
 use std::mem::ManuallyDrop;
 use std::os::raw::{c_char, c_longlong, c_uchar, c_void};
+use std::sync::atomic::{AtomicU64, Ordering};
 
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Allocates id of the asynchronous request or of the background task
+pub fn next_request_id() -> u64 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Allocates id of the new asynchronous request, writing it into `output` unless it's null
+pub unsafe fn new_request(output: *mut u64) -> u64 {
+    let request_id = next_request_id();
+    if !output.is_null() {
+        *output = request_id;
+    }
+    request_id
+}
+
+/// Stable name of the error, sent as `code` of the failed [`AsyncReply`]
+pub trait ReplyErrorCode {
+    fn code(&self) -> &'static str;
+
+    /// Part of the work, done before the failure, sent as `payload` of the failed [`AsyncReply`]
+    fn payload(&self) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+impl ReplyErrorCode for anyhow::Error {
+    fn code(&self) -> &'static str {
+        "Failed"
+    }
+}
+
+/// Json envelope, posted to the answer port once the asynchronous request is finished:
+/// `{"request_id": 1, "status": "ok", "payload": ..}`,
+/// `{"request_id": 1, "status": "error", "code": "InvalidPassword", "message": .., "payload": ..}`,
+/// where `payload` is present only if the request has partial results, or
+/// `{"request_id": 1, "status": "panic", "message": ..}` or
+/// `{"request_id": 1, "status": "cancelled"}`.
+/// Long requests may post any number of `{"request_id": 1, "status": "progress", "payload": ..}`
+/// before the final reply.
 /// cbindgen:ignore
-#[derive(Deserialize, Serialize)]
-pub enum StringResult {
-    Ok(String),
-    Error(String),
+#[derive(Serialize)]
+pub struct AsyncReply<T> {
+    pub request_id: u64,
+    #[serde(flatten)]
+    pub result: ReplyResult<T>,
+}
+
+/// cbindgen:ignore
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ReplyResult<T> {
+    Ok {
+        payload: T,
+    },
+    Error {
+        code: &'static str,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<serde_json::Value>,
+    },
+    Panic {
+        message: String,
+    },
+    /// Request was cancelled by `cancel_operation`
+    Cancelled,
+    /// Intermediate state of the request, it isn't finished yet
+    Progress {
+        payload: T,
+    },
+}
+
+impl<T: Serialize> AsyncReply<T> {
+    pub fn ok(request_id: u64, payload: T) -> Self {
+        Self {
+            request_id,
+            result: ReplyResult::Ok { payload },
+        }
+    }
+
+    pub fn progress(request_id: u64, payload: T) -> Self {
+        Self {
+            request_id,
+            result: ReplyResult::Progress { payload },
+        }
+    }
+
+    pub fn cancelled(request_id: u64) -> Self {
+        Self {
+            request_id,
+            result: ReplyResult::Cancelled,
+        }
+    }
+
+    /// Wraps outcome of the request, as returned by [`crate::panic::catch_panic_async`]
+    pub fn new<E>(request_id: u64, result: Result<Result<T, E>, String>) -> Self
+    where
+        E: ReplyErrorCode + std::fmt::Display,
+    {
+        let result = match result {
+            Ok(Ok(payload)) => ReplyResult::Ok { payload },
+            Ok(Err(e)) => ReplyResult::Error {
+                code: e.code(),
+                message: e.to_string(),
+                payload: e.payload(),
+            },
+            Err(message) => ReplyResult::Panic { message },
+        };
+        Self { request_id, result }
+    }
+
+    pub fn post(&self, port: &SendPort) -> bool {
+        match serde_json::to_string(self) {
+            Ok(data) => port.post(data),
+            Err(e) => {
+                log::error!("Failed serializing reply: {}", e);
+                let reply = AsyncReply::<()> {
+                    request_id: self.request_id,
+                    result: ReplyResult::Error {
+                        code: "Failed",
+                        message: e.to_string(),
+                        payload: None,
+                    },
+                };
+                port.post(serde_json::to_string(&reply).unwrap_or_default())
+            }
+        }
+    }
 }
 
 /// cbindgen:ignore
@@ -470,3 +594,40 @@ impl<T> IntoDart for *mut T {
 
 /// cbindgen:ignore
 pub static mut POST_COBJECT: Option<DartPostCObjectFnType> = None;
+
+/// Records messages, posted to Dart ports, so that tests can check them
+#[cfg(test)]
+pub mod test_port {
+    use std::sync::Mutex;
+
+    use once_cell::sync::Lazy;
+
+    use super::{DartCObject, DartCObjectPtr, DartPort, SendPort, POST_COBJECT};
+
+    static POSTED: Lazy<Mutex<Vec<(DartPort, String)>>> = Lazy::new(Default::default);
+
+    unsafe extern "C" fn record(port: DartPort, message: DartCObjectPtr) -> u8 {
+        let message = &*(message as *const DartCObject);
+        let message = std::ffi::CStr::from_ptr(message.value.as_string);
+        let message = message.to_string_lossy().into_owned();
+        POSTED.lock().unwrap().push((port, message));
+        1
+    }
+
+    /// Port, whose messages are recorded. Every test must use its own port number
+    pub fn listen(port: DartPort) -> SendPort {
+        unsafe { POST_COBJECT = Some(record) };
+        SendPort::new(port)
+    }
+
+    /// Messages, posted to `port`
+    pub fn posted(port: DartPort) -> Vec<String> {
+        POSTED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(posted, _)| *posted == port)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+}
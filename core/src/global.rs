@@ -1,12 +1,21 @@
-
-
 use once_cell::sync::Lazy;
 
-
+use crate::context::{Context, TaskManager};
+use crate::handles::HandleTable;
+use crate::wrappers::storage::{KeyStoreWrapper, NativeStorage};
+use crate::{GqlTransport, TonWalletSubscription};
 
 pub static RUNTIME_: Lazy<std::io::Result<tokio::runtime::Runtime>> =
     Lazy::new(tokio::runtime::Runtime::new);
 
+pub static CONTEXTS: Lazy<HandleTable<Context>> = Lazy::new(Default::default);
+pub static STORAGES: Lazy<HandleTable<NativeStorage>> = Lazy::new(Default::default);
+pub static KEYSTORES: Lazy<HandleTable<KeyStoreWrapper>> = Lazy::new(Default::default);
+pub static TRANSPORTS: Lazy<HandleTable<GqlTransport>> = Lazy::new(Default::default);
+/// Wallet subscriptions of the contexts, registered by [`Context::new`]
+pub static SUBSCRIPTIONS: Lazy<HandleTable<TonWalletSubscription>> = Lazy::new(Default::default);
+/// Asynchronous requests, which are not bound to any context
+pub static OPERATIONS: Lazy<TaskManager> = Lazy::new(Default::default);
 
 #[macro_export]
 macro_rules! get_runtime {
@@ -27,7 +36,11 @@ macro_rules! get_runtime {
             }
             Err(e) => {
                 ::log::error!("Failed getting tokio runtime: {}", e);
-                return crate::ExitCode::FailedToCreateRuntime;
+                return crate::fail!(
+                    crate::ExitCode::FailedToCreateRuntime,
+                    format!("Failed getting tokio runtime: {}", e),
+                    Vec::new()
+                );
             }
         }
     };
@@ -0,0 +1,180 @@
+use std::sync::{Arc, Mutex};
+
+/// Id of the object, handed to Dart instead of a raw pointer.
+///
+/// Low 32 bits are the slot index, high 32 bits are the generation of the slot,
+/// so an id of the deleted object never resolves to the object, which reused its slot.
+/// `0` is never a valid handle.
+pub type Handle = u64;
+
+/// Table of objects, owned by Rust and referenced by [`Handle`]s from Dart
+pub struct HandleTable<T> {
+    inner: Mutex<Slots<T>>,
+}
+
+struct Slots<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<Arc<T>>,
+}
+
+impl<T> Default for HandleTable<T> {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Slots {
+                slots: Vec::new(),
+                free: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl<T> HandleTable<T> {
+    pub fn insert(&self, value: T) -> Handle {
+        self.insert_arc(Arc::new(value))
+    }
+
+    pub fn insert_arc(&self, value: Arc<T>) -> Handle {
+        let mut inner = self.lock();
+        let index = match inner.free.pop() {
+            Some(index) => index,
+            None => {
+                inner.slots.push(Slot {
+                    generation: 0,
+                    value: None,
+                });
+                (inner.slots.len() - 1) as u32
+            }
+        };
+        let slot = &mut inner.slots[index as usize];
+        // Generation is never 0, so no handle is 0
+        slot.generation = slot.generation.wrapping_add(1).max(1);
+        slot.value = Some(value);
+        make_handle(index, slot.generation)
+    }
+
+    /// Returns object of the `handle`, if it's still alive
+    pub fn get(&self, handle: Handle) -> Option<Arc<T>> {
+        let (index, generation) = split_handle(handle);
+        let inner = self.lock();
+        match inner.slots.get(index as usize) {
+            Some(slot) if slot.generation == generation => slot.value.clone(),
+            _ => None,
+        }
+    }
+
+    /// Removes object of the `handle` from the table.
+    /// The object itself is dropped, once the last running operation releases it
+    pub fn remove(&self, handle: Handle) -> Option<Arc<T>> {
+        let (index, generation) = split_handle(handle);
+        let mut inner = self.lock();
+        let value = match inner.slots.get_mut(index as usize) {
+            Some(slot) if slot.generation == generation => slot.value.take(),
+            _ => None,
+        };
+        if value.is_some() {
+            inner.free.push(index);
+        }
+        value
+    }
+
+    /// Returns every alive object
+    pub fn values(&self) -> Vec<Arc<T>> {
+        self.lock()
+            .slots
+            .iter()
+            .filter_map(|slot| slot.value.clone())
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Slots<T>> {
+        // Table stays consistent even if some thread panicked while holding the lock
+        match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(e) => e.into_inner(),
+        }
+    }
+}
+
+fn make_handle(index: u32, generation: u32) -> Handle {
+    ((generation as u64) << 32) | index as u64
+}
+
+fn split_handle(handle: Handle) -> (u32, u32) {
+    (handle as u32, (handle >> 32) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_inserted_values() {
+        let table = HandleTable::default();
+        let first = table.insert("first");
+        let second = table.insert("second");
+
+        assert_ne!(first, 0);
+        assert_ne!(first, second);
+        assert_eq!(table.get(first).as_deref(), Some(&"first"));
+        assert_eq!(table.get(second).as_deref(), Some(&"second"));
+        assert_eq!(table.get(0), None);
+    }
+
+    #[test]
+    fn removed_handle_is_dead() {
+        let table = HandleTable::default();
+        let handle = table.insert(1);
+
+        assert_eq!(table.remove(handle).as_deref(), Some(&1));
+        assert_eq!(table.get(handle), None);
+        assert_eq!(table.remove(handle), None);
+        assert!(table.values().is_empty());
+    }
+
+    #[test]
+    fn reused_slot_gets_new_generation() {
+        let table = HandleTable::default();
+        let old = table.insert(1);
+        table.remove(old);
+        let new = table.insert(2);
+
+        assert_eq!(split_handle(old).0, split_handle(new).0);
+        assert_ne!(old, new);
+        assert_eq!(table.get(old), None);
+        assert_eq!(table.remove(old), None);
+        assert_eq!(table.get(new).as_deref(), Some(&2));
+    }
+
+    #[test]
+    fn generation_skips_zero_on_wrap() {
+        let table = HandleTable::default();
+        table.insert(1);
+        table.lock().slots[0].generation = u32::MAX;
+        table.remove(make_handle(0, u32::MAX));
+
+        assert_eq!(split_handle(table.insert(2)), (0, 1));
+    }
+
+    #[test]
+    fn unknown_index_is_rejected() {
+        let table = HandleTable::<u32>::default();
+        assert_eq!(table.get(make_handle(5, 1)), None);
+        assert_eq!(table.remove(make_handle(5, 1)), None);
+    }
+
+    #[test]
+    fn removed_value_outlives_table_entry() {
+        let table = HandleTable::default();
+        let handle = table.insert(String::from("value"));
+        let value = table.get(handle).unwrap();
+        table.remove(handle);
+
+        assert_eq!(*value, "value");
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+}
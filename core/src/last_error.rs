@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::ExitCode;
+
+thread_local! {
+    /// Error of the last failed FFI call made from this thread
+    static LAST_ERROR: RefCell<Option<LastError>> = RefCell::new(None);
+}
+
+#[derive(Serialize)]
+pub struct LastError {
+    /// Numeric value of the returned [`ExitCode`]
+    code: i32,
+    /// Name of the returned [`ExitCode`]
+    name: String,
+    message: String,
+    /// Messages of the underlying errors, outermost first
+    chain: Vec<String>,
+}
+
+/// Stores error of the current thread, replacing the previous one
+pub fn set_last_error(code: ExitCode, message: String, chain: Vec<String>) {
+    let error = LastError {
+        code: code as i32,
+        name: format!("{:?}", code),
+        message,
+        chain,
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error));
+}
+
+/// Forgets error of the current thread, done at the start of every FFI call
+pub fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// Returns error of the last failed call of the current thread, if any, clearing it
+pub fn take_last_error() -> Option<LastError> {
+    LAST_ERROR.with(|last| last.borrow_mut().take())
+}
+
+/// Collects messages of the error and its sources.
+///
+/// Errors passing through the macros are either `anyhow::Error`, std errors or
+/// foreign ones, which only implement `Display`. Method lookup picks the most
+/// specific impl by the number of references, see [`crate::error_chain`]
+pub struct ErrorChain<'a, T>(pub &'a T);
+
+pub trait AnyhowChain {
+    fn messages(&self) -> Vec<String>;
+}
+
+impl AnyhowChain for &&ErrorChain<'_, anyhow::Error> {
+    fn messages(&self) -> Vec<String> {
+        self.0.chain().map(ToString::to_string).collect()
+    }
+}
+
+pub trait StdErrorChain {
+    fn messages(&self) -> Vec<String>;
+}
+
+impl<T: std::error::Error> StdErrorChain for &ErrorChain<'_, T> {
+    fn messages(&self) -> Vec<String> {
+        let mut messages = vec![self.0.to_string()];
+        let mut source = self.0.source();
+        while let Some(error) = source {
+            messages.push(error.to_string());
+            source = error.source();
+        }
+        messages
+    }
+}
+
+pub trait DisplayChain {
+    fn messages(&self) -> Vec<String>;
+}
+
+impl<T: Display> DisplayChain for ErrorChain<'_, T> {
+    fn messages(&self) -> Vec<String> {
+        vec![self.0.to_string()]
+    }
+}
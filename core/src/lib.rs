@@ -1,4 +1,4 @@
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int, c_longlong, c_uint};
 use std::sync::Arc;
 
@@ -19,9 +19,13 @@ use tokio::time::Duration;
 use crate::context::{Context, TaskManager};
 use crate::external::GqlConnection;
 use crate::ffi::IntoDart;
+use crate::global::{CONTEXTS, KEYSTORES, OPERATIONS, STORAGES, SUBSCRIPTIONS, TRANSPORTS};
+use crate::handles::Handle;
+use crate::panic::{catch_panic, catch_panic_keeping_error};
 pub use crate::wrappers::send;
 use crate::wrappers::storage;
-use crate::wrappers::storage::NativeStorage;
+use crate::wrappers::storage::KeyStoreWrapper;
+use crate::wrappers::{Confirmations, MultisigTransaction, Outcome, PendingTracker};
 
 mod external;
 mod ffi;
@@ -29,8 +33,10 @@ mod wrappers;
 
 mod context;
 mod global;
+mod handles;
+mod last_error;
 pub(crate) mod macros;
-mod utils;
+mod panic;
 
 pub struct Runtime {}
 
@@ -51,29 +57,63 @@ impl Runtime {
     }
 }
 
+/// Releases storage handle. Keystores, opened over the storage, keep using it
+#[no_mangle]
+pub unsafe extern "C" fn delete_storage(storage: Handle) -> ExitCode {
+    catch_panic(|| match STORAGES.remove(storage) {
+        Some(_) => ExitCode::Ok,
+        None => fail!(
+            ExitCode::StorageIsNotInitialized,
+            format!("Invalid handle storage: {}", storage),
+            Vec::new()
+        ),
+    })
+}
+
+/// Creates storage from `data` json. If `path` is not null, the storage is persisted to that file,
+/// `data` is then used only if the file doesn't exist yet and may be null.
+/// If `key` (hex encoded, 32 bytes) is not null, values are encrypted at rest, which is recorded
+/// in the header of the stored document. Storage without the header is rejected then, unless
+/// `seal_plaintext` is set to migrate unencrypted storage, then its values are encrypted.
 #[no_mangle]
 pub unsafe extern "C" fn create_storage(
     data: *const c_char,
-    storage_ptr: *mut *const NativeStorage,
+    path: *const c_char,
+    key: *const c_char,
+    seal_plaintext: bool,
+    storage_handle: *mut Handle,
 ) -> ExitCode {
-    if data.is_null() {
-        return ExitCode::InvalidUrl;
-    }
-    let data = match CStr::from_ptr(data).to_str() {
-        Ok(a) => a,
-        Err(_) => {
-            return ExitCode::InvalidUrl;
-        }
-    };
-    let storage = match storage::NativeStorage::new(data) {
-        Ok(a) => a,
-        Err(_) => {
-            return ExitCode::InvalidUrl;
-        }
-    };
+    catch_panic(|| {
+        ffi_ensure!(
+            storage_handle.is_null(),
+            ExitCode::NullOutputPointer,
+            "Storage output is null"
+        );
+        ffi_ensure!(
+            data.is_null() && path.is_null(),
+            ExitCode::BadKeystoreData,
+            "Both data and path are null"
+        );
+        let storage = match get_runtime!().block_on(storage::ffi::create_native_storage(
+            data,
+            path,
+            key,
+            seal_plaintext,
+        )) {
+            Ok(a) => a,
+            Err(e) => {
+                log::error!("Failed creating storage: {}", e);
+                return fail!(
+                    storage::ffi::create_storage_error_code(&e),
+                    format!("Failed creating storage: {}", e),
+                    error_chain!(e)
+                );
+            }
+        };
 
-    *storage_ptr = Box::into_raw(Box::new(storage));
-    ExitCode::Ok
+        *storage_handle = STORAGES.insert(storage);
+        ExitCode::Ok
+    })
 }
 
 pub struct TonWallet {
@@ -86,15 +126,97 @@ pub unsafe extern "C" fn init(post_cobject: ffi::DartPostCObjectFnType) {
     ffi::POST_COBJECT = Some(post_cobject);
 }
 
+/// Writes json of the last error of the calling thread into `output`:
+/// `{"code": 5, "name": "FailedToAddKey", "message": "...", "chain": ["...", "..."]}`.
+/// Every other call resets the error, so null is written if the last call succeeded
+/// or the error was already retrieved. The string must be freed with [`free_cstring`]
 #[no_mangle]
-pub unsafe extern "C" fn wait(seconds: c_uint, send_port: c_longlong) -> ExitCode {
-    get_runtime!().spawn(async move {
-        tokio::time::sleep(std::time::Duration::from_secs(seconds as u64)).await;
+pub unsafe extern "C" fn get_last_error(output: *mut *const c_char) -> ExitCode {
+    catch_panic_keeping_error(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        *output = match last_error::take_last_error() {
+            Some(error) => {
+                let error = ok_or_ret!(
+                    serde_json::to_string(&error),
+                    ExitCode::FailedToGetLastError
+                );
+                ok_or_ret!(CString::new(error), ExitCode::FailedToGetLastError).into_raw()
+            }
+            None => std::ptr::null(),
+        };
+        ExitCode::Ok
+    })
+}
+
+/// Frees string, written into `output` by any of the exported functions. Null is ignored.
+///
+/// Every string returned by the library is allocated by Rust and must be freed here exactly once.
+/// Strings passed into the library are only borrowed for the duration of the call
+/// and stay owned by the caller
+#[no_mangle]
+pub unsafe extern "C" fn free_cstring(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
 
-        ffi::SendPort::new(send_port).post(());
-    });
+/// Writes message of the last panic, caught at the FFI boundary, into `output`.
+/// Writes null if there was no panic since the last call. The string must be freed with [`free_cstring`]
+#[no_mangle]
+pub unsafe extern "C" fn get_last_panic(output: *mut *const c_char) -> ExitCode {
+    catch_panic_keeping_error(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        *output = match panic::take_last_panic() {
+            Some(message) => CString::new(message).unwrap_or_default().into_raw(),
+            None => std::ptr::null(),
+        };
+        ExitCode::Ok
+    })
+}
+
+/// Posts [`ffi::AsyncReply`] with null payload to `send_port` after `seconds`.
+/// Id of the request is written into `request_id`, unless it's null
+#[no_mangle]
+pub unsafe extern "C" fn wait(
+    seconds: c_uint,
+    send_port: c_longlong,
+    request_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        let request_id = ffi::new_request(request_id);
+        let port = ffi::SendPort::new(send_port);
+        OPERATIONS.spawn_request("wait", request_id, port, async move {
+            tokio::time::sleep(std::time::Duration::from_secs(seconds as u64)).await;
+            Ok::<_, anyhow::Error>(())
+        })
+    })
+}
 
-    ExitCode::Ok
+/// Cancels asynchronous operation `id`, i.e. the request id written by the call which started it.
+/// The answer port of the operation receives cancelled [`ffi::AsyncReply`] instead of the result
+#[no_mangle]
+pub unsafe extern "C" fn cancel_operation(id: u64) -> ExitCode {
+    catch_panic(|| {
+        let cancelled = OPERATIONS.cancel(id)
+            || CONTEXTS
+                .values()
+                .iter()
+                .any(|context| context.manager.cancel(id));
+        ffi_ensure!(
+            !cancelled,
+            ExitCode::OperationNotFound,
+            "Operation not found"
+        );
+        ExitCode::Ok
+    })
 }
 
 pub struct GqlTransport {
@@ -109,73 +231,157 @@ impl GqlTransport {
     }
 }
 
+/// Creates context, subscribed to the wallet of `public_key`.
+/// `keystore` is a handle from `open_keystore`, shared with the context, so it can be used
+/// for key management while the context is alive
 #[no_mangle]
 pub unsafe extern "C" fn create_context(
     params: TransportParams,
     public_key: *const c_char,
     contract_type: ContractType,
     subscription_port: c_longlong,
-    keystore_data: *mut c_char,
-    context_ffi: *mut *mut Context,
+    keystore: Handle,
+    context_handle: *mut Handle,
 ) -> ExitCode {
-    let manager = TaskManager::default();
+    catch_panic(|| {
+        ffi_ensure!(
+            context_handle.is_null(),
+            ExitCode::NullOutputPointer,
+            "Context output is null"
+        );
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let keystore = (*keystore).clone();
+        let manager = TaskManager::default();
+
+        let transport = Arc::new(ok_or_ret!(
+            create_gql_transport(params),
+            ExitCode::InvalidUrl
+        ));
+        let wallet = match get_runtime!().block_on(subscribe_to_ton_wallet(
+            manager.clone(),
+            public_key,
+            contract_type,
+            transport.inner.clone(),
+            subscription_port,
+        )) {
+            Ok(a) => a,
+            Err(e) => {
+                return e;
+            }
+        };
+        let context = Context::new(wallet, transport, keystore, manager);
 
-    let transport = match create_gql_transport(params) {
-        None => return ExitCode::InvalidUrl,
-        Some(a) => Arc::new(a),
-    };
-    let wallet = match get_runtime!().block_on(subscribe_to_ton_wallet(
-        manager.clone(),
-        public_key,
-        contract_type,
-        transport.inner.clone(),
-        subscription_port,
-    )) {
-        Ok(a) => a,
-        Err(e) => {
-            return e;
-        }
-    };
-    let keystore = match get_runtime!().block_on(crate::wrappers::storage::ffi::create_keystore(
-        keystore_data,
-    )) {
-        Ok(a) => a,
-        Err(e) => {
-            return e;
+        *context_handle = CONTEXTS.insert(context);
+        ExitCode::Ok
+    })
+}
+
+/// Deletes the context with its wallet subscription,
+/// forgetting keys of every unlock session of its keystore
+#[no_mangle]
+pub unsafe extern "C" fn delete_context(context: Handle) -> ExitCode {
+    catch_panic(|| {
+        let context = match CONTEXTS.remove(context) {
+            Some(context) => context,
+            None => {
+                return fail!(
+                    ExitCode::NoContextProvided,
+                    "Invalid handle context".to_string(),
+                    Vec::new()
+                )
+            }
+        };
+        // Subscription may be already deleted on its own
+        if let Some(subscription) = SUBSCRIPTIONS.remove(context.subscription) {
+            subscription.stop();
         }
-    };
-    let context = Box::new(Context::new(wallet, transport, keystore, manager));
+        get_runtime!().block_on(close_keystore(&context.keystore));
+        ExitCode::Ok
+    })
+}
 
-    *context_ffi = Box::into_raw(context);
-    ExitCode::Ok
+/// Writes handle of the context wallet subscription into `subscription_handle`
+#[no_mangle]
+pub unsafe extern "C" fn get_subscription(
+    context: Handle,
+    subscription_handle: *mut Handle,
+) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            subscription_handle.is_null(),
+            ExitCode::NullOutputPointer,
+            "Subscription output is null"
+        );
+        let context = get_handle!(CONTEXTS, context, ExitCode::NoContextProvided);
+        *subscription_handle = context.subscription;
+        ExitCode::Ok
+    })
 }
 
+/// Stops refreshing the wallet, so no more updates are posted to the subscription port.
+/// The context keeps working with the last known state of the wallet
 #[no_mangle]
-pub unsafe extern "C" fn delete_context(context: *mut Context) -> ExitCode {
-    if context.is_null() {
-        return ExitCode::NoContextProvided;
-    }
-    Box::from_raw(context);
-    ExitCode::Ok
+pub unsafe extern "C" fn delete_subscription(subscription: Handle) -> ExitCode {
+    catch_panic(|| match SUBSCRIPTIONS.remove(subscription) {
+        Some(removed) => {
+            removed.stop();
+            ExitCode::Ok
+        }
+        None => fail!(
+            ExitCode::SubscriptionIsNotInitialized,
+            format!("Invalid handle subscription: {}", subscription),
+            Vec::new()
+        ),
+    })
+}
+
+/// Forgets keys, unlocked with the context. Sessions are shared by every handle of the keystore,
+/// so they would outlive the context otherwise
+async fn close_keystore(keystore: &KeyStoreWrapper) {
+    keystore.sessions().lock_all().await;
 }
 
 #[repr(C)]
 pub struct TransportParams {
-    pub url: *mut c_char,
+    pub url: *const c_char,
 }
 
-unsafe fn create_gql_transport(params: TransportParams) -> Option<GqlTransport> {
-    let url = CStr::from_ptr(params.url).to_str().ok()?;
-    GqlConnection::new(url).map(GqlTransport::new).ok()
+unsafe fn create_gql_transport(params: TransportParams) -> Result<GqlTransport> {
+    if params.url.is_null() {
+        return Err(NekotonError::NullPointerPassed.into());
+    }
+    let url = CStr::from_ptr(params.url).to_str()?;
+    GqlConnection::new(url).map(GqlTransport::new)
 }
 
+/// Creates transport, which is not bound to any wallet, e.g. to look for existing wallets
 #[no_mangle]
-pub unsafe extern "C" fn delete_gql_transport(gql_transport: *mut GqlTransport) -> ExitCode {
-    if gql_transport.is_null() {
-        return ExitCode::TransportIsNotInitialized;
-    }
-    Box::from_raw(gql_transport);
-    ExitCode::Ok
+pub unsafe extern "C" fn create_transport(
+    params: TransportParams,
+    transport_handle: *mut Handle,
+) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            transport_handle.is_null(),
+            ExitCode::NullOutputPointer,
+            "Transport output is null"
+        );
+        let transport = ok_or_ret!(create_gql_transport(params), ExitCode::InvalidUrl);
+        *transport_handle = TRANSPORTS.insert(transport);
+        ExitCode::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn delete_gql_transport(gql_transport: Handle) -> ExitCode {
+    catch_panic(|| match TRANSPORTS.remove(gql_transport) {
+        Some(_) => ExitCode::Ok,
+        None => fail!(
+            ExitCode::TransportIsNotInitialized,
+            format!("Invalid handle gql_transport: {}", gql_transport),
+            Vec::new()
+        ),
+    })
 }
 
 pub async fn subscribe_to_ton_wallet(
@@ -187,7 +393,13 @@ pub async fn subscribe_to_ton_wallet(
 ) -> Result<TonWalletSubscription, ExitCode> {
     let public_key = match read_public_key(public_key) {
         Ok(key) => key,
-        Err(_) => return Err(ExitCode::InvalidPublicKey),
+        Err(e) => {
+            return Err(fail!(
+                ExitCode::InvalidPublicKey,
+                format!("Invalid public key: {}", e),
+                error_chain!(e)
+            ))
+        }
     };
     let contract_type = contract_type.into();
 
@@ -195,44 +407,79 @@ pub async fn subscribe_to_ton_wallet(
         "address: {}",
         compute_address(&public_key, contract_type, 0).to_string()
     );
-    let handler = Arc::new(TonWalletSubscriptionHandler::new(subscription_port));
-    match ton_wallet::TonWallet::subscribe(transport, public_key, contract_type, handler).await {
+    let confirmations = Confirmations::default();
+    let handler = Arc::new(TonWalletSubscriptionHandler::new(
+        subscription_port,
+        confirmations.clone(),
+    ));
+    match ton_wallet::TonWallet::subscribe(
+        transport.clone(),
+        public_key,
+        contract_type,
+        handler.clone(),
+    )
+    .await
+    {
         Ok(new_subscription) => {
             let mut wallet = new_subscription.clone();
+            let mut pending = PendingTracker::new(&wallet);
+            let refresh_id = ffi::next_request_id();
             let wallet_subscription = TonWalletSubscription {
                 inner: new_subscription,
+                confirmations,
+                manager: manager.clone(),
+                refresh_id,
             };
-            let handle = tokio::spawn(async move {
+            let refresh = manager.spawn("refresh_wallet", refresh_id, async move {
                 loop {
                     if let Err(e) = wallet.refresh().await {
                         log::error!("Failed refreshing: {}", e);
                     }
+                    if let Some(pending) = &mut pending {
+                        match pending.poll(&wallet, transport.as_ref()).await {
+                            Ok(found) if !found.is_empty() => {
+                                handler.on_pending_transactions_found(found)
+                            }
+                            Ok(_) => {}
+                            Err(e) => log::error!("Failed polling pending transactions: {}", e),
+                        }
+                    }
                     tokio::time::sleep(Duration::from_secs(10)).await;
                 }
             });
-            manager.track(handle).await;
-            Ok(wallet_subscription)
+            match refresh {
+                ExitCode::Ok => Ok(wallet_subscription),
+                e => Err(e),
+            }
         }
-        Err(_) => Err(ExitCode::FailedToSubscribeToTonWallet),
-    }
-}
-
-#[no_mangle]
-pub unsafe extern "C" fn delete_subscription(subscription: *mut TonWalletSubscription) -> ExitCode {
-    if subscription.is_null() {
-        return ExitCode::SubscriptionIsNotInitialized;
+        Err(e) => Err(fail!(
+            ExitCode::FailedToSubscribeToTonWallet,
+            format!("Failed subscribing to the wallet: {}", e),
+            error_chain!(e)
+        )),
     }
-    Box::from_raw(subscription);
-    ExitCode::Ok
 }
 
 #[derive(Clone)]
 pub struct TonWalletSubscription {
     inner: ton_wallet::TonWallet,
+    /// Outcomes of the messages sent by the wallet
+    confirmations: Confirmations,
+    /// Manager of the context, running the refresh task
+    manager: TaskManager,
+    refresh_id: u64,
+}
+
+impl TonWalletSubscription {
+    /// Stops refreshing the wallet
+    fn stop(&self) {
+        self.manager.cancel(self.refresh_id);
+    }
 }
 
 struct TonWalletSubscriptionHandler {
     port: ffi::SendPort,
+    confirmations: Confirmations,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -241,11 +488,16 @@ enum OnUpdate {
     OnTransactionsFound(OnTransactionsFound),
     OnMessageExpired(PendingTransaction),
     OnStateChanged(ContractState),
+    /// Transactions of the multisig, which appeared since the last update and wait for confirmations
+    OnPendingTransactionsFound(Vec<MultisigTransaction>),
 }
 
 impl OnUpdate {
     fn prepare(&self) -> String {
-        serde_json::to_string(self).unwrap()
+        serde_json::to_string(self).unwrap_or_else(|e| {
+            log::error!("Failed serializing update: {}", e);
+            String::new()
+        })
     }
 }
 
@@ -262,11 +514,18 @@ struct OnTransactionsFound {
 }
 
 impl TonWalletSubscriptionHandler {
-    pub fn new(port: i64) -> Self {
+    pub fn new(port: i64, confirmations: Confirmations) -> Self {
         Self {
             port: ffi::SendPort::new(port),
+            confirmations,
         }
     }
+
+    fn on_pending_transactions_found(&self, transactions: Vec<MultisigTransaction>) {
+        log::debug!("on_pending_transactions_found");
+        self.port
+            .post(OnUpdate::OnPendingTransactionsFound(transactions).prepare());
+    }
 }
 
 impl ton_wallet::TonWalletSubscriptionHandler for TonWalletSubscriptionHandler {
@@ -277,6 +536,8 @@ impl ton_wallet::TonWalletSubscriptionHandler for TonWalletSubscriptionHandler {
     ) {
         // log::debug!("{:?} {:?}", &pending_transaction, &transaction);
         log::debug!("on_message_sent");
+        self.confirmations
+            .resolve(&pending_transaction, Outcome::Sent(transaction.clone()));
         self.port.post(
             OnUpdate::OnMessageSent(OnMessageSent {
                 pending_transaction,
@@ -289,6 +550,8 @@ impl ton_wallet::TonWalletSubscriptionHandler for TonWalletSubscriptionHandler {
     fn on_message_expired(&self, pending_transaction: PendingTransaction) {
         // log::debug!("{:?}", &pending_transaction);
         log::debug!("on_message_expired");
+        self.confirmations
+            .resolve(&pending_transaction, Outcome::Expired);
         self.port
             .post(OnUpdate::OnMessageExpired(pending_transaction).prepare());
     }
@@ -328,6 +591,7 @@ fn read_public_key(public_key: *const c_char) -> Result<PublicKey> {
 }
 
 #[repr(C)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub enum ContractType {
     SafeMultisig,
     SafeMultisig24h,
@@ -336,6 +600,16 @@ pub enum ContractType {
     WalletV3,
 }
 
+impl ContractType {
+    pub const ALL: [ContractType; 5] = [
+        ContractType::SafeMultisig,
+        ContractType::SafeMultisig24h,
+        ContractType::SetcodeMultisig,
+        ContractType::Surf,
+        ContractType::WalletV3,
+    ];
+}
+
 impl From<ContractType> for ton_wallet::ContractType {
     fn from(t: ContractType) -> Self {
         match t {
@@ -357,13 +631,16 @@ impl From<ContractType> for ton_wallet::ContractType {
 }
 
 #[derive(thiserror::Error, Debug)]
-enum NekotonError {
+pub enum NekotonError {
     #[error("Null pointer passed")]
     NullPointerPassed,
+    #[error("Unknown {0}: {1}")]
+    UnknownEnumValue(&'static str, u32),
 }
 
+/// Values are a part of the C ABI: new variants are only appended to the end
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum ExitCode {
     Ok = 0,
 
@@ -393,6 +670,31 @@ pub enum ExitCode {
     BadCreateKeyData,
     BadUpdateData,
     BadExportData,
+
+    /// Panic was caught, the message can be retrieved with `get_last_panic`
+    Panic,
+    StorageIsNotInitialized,
+    FailedToGetEntries,
+    FailedToGeneratePhrase,
+    FailedToDeriveAccounts,
+    FailedToSign,
+    FailedToExportBackup,
+    FailedToDumpStorage,
+    FailedToRekeyStorage,
+    FailedToGetLastError,
+    OperationNotFound,
+    BadStorageKey,
+    BadPhrase,
+    BadDeriveData,
+    BadBackup,
+    BadRetryPolicy,
+    BadDeployParams,
+    NotMultisig,
+    BadTransferParams,
+    FailedToAccessStorage,
+    ReservedNamespace,
+    /// Enum argument is out of range
+    BadEnumValue,
 }
 
 impl IntoDart for ExitCode {
@@ -400,3 +702,68 @@ impl IntoDart for ExitCode {
         (self as c_int).into_dart()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrappers::storage::session::UnlockedKey;
+    use crate::wrappers::SignData;
+
+    #[test]
+    fn exit_codes_keep_abi_values() {
+        assert_eq!(ExitCode::FailedToCreateRuntime as c_int, 1);
+        assert_eq!(ExitCode::InvalidPublicKey as c_int, 12);
+        assert_eq!(ExitCode::NoContextProvided as c_int, 14);
+        assert_eq!(ExitCode::BadExportData as c_int, 23);
+        assert_eq!(ExitCode::Panic as c_int, 24);
+    }
+
+    #[tokio::test]
+    async fn deleted_context_locks_sessions() {
+        let storage = storage::NativeStorage::new("{}", None, false).unwrap();
+        let keystore = KeyStoreWrapper::open(storage).await.unwrap();
+        // Shares sessions, as handles of the keystore do
+        let handle = keystore.clone();
+
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[1; 32]).unwrap();
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        let key = UnlockedKey::new(ed25519_dalek::Keypair { secret, public });
+        let id = keystore
+            .sessions()
+            .unlock(Arc::new(key), Duration::from_secs(60))
+            .await;
+        assert!(handle
+            .sessions()
+            .resolve(SignData::Session { id })
+            .await
+            .is_ok());
+
+        close_keystore(&keystore).await;
+        assert!(handle
+            .sessions()
+            .resolve(SignData::Session { id })
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn delete_subscription_rejects_unknown_handle() {
+        unsafe {
+            assert!(matches!(
+                delete_subscription(0),
+                ExitCode::SubscriptionIsNotInitialized
+            ));
+        }
+    }
+
+    #[test]
+    fn cancel_operation_aborts_operation_once() {
+        let id = ffi::next_request_id();
+        OPERATIONS.spawn("test", id, futures::future::pending());
+
+        unsafe {
+            assert!(matches!(cancel_operation(id), ExitCode::Ok));
+            assert!(matches!(cancel_operation(id), ExitCode::OperationNotFound));
+        }
+    }
+}
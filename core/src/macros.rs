@@ -1,15 +1,21 @@
-/// Converts c string to String
+/// Copies borrowed c string into String. The pointer stays owned by the caller
 #[macro_export]
 macro_rules! cstr_to_string {
-    ($stri:expr, $ret_val:expr) => {
-        match ::std::ffi::CString::from_raw($stri).to_str() {
+    ($stri:expr, $ret_val:expr) => {{
+        if $stri.is_null() {
+            ::log::error!("Null pointer passed as {}", stringify!($stri));
+            let message = format!("Null pointer passed as {}", stringify!($stri));
+            return $crate::fail!($ret_val, message, Vec::new());
+        }
+        match ::std::ffi::CStr::from_ptr($stri).to_str() {
             Ok(a) => a.to_string(),
             Err(e) => {
                 ::log::error!("Failed decoding {}: {}", stringify!($stri), e);
-                return $ret_val;
+                let message = format!("Failed decoding {}: {}", stringify!($stri), e);
+                return $crate::fail!($ret_val, message, $crate::error_chain!(e));
             }
         }
-    };
+    }};
 }
 
 ///Matches expression, returning provided expression in case of error;
@@ -20,7 +26,8 @@ macro_rules! ok_or_ret {
             Ok(a) => a,
             Err(e) => {
                 ::log::error!("Failed with {}: {}", stringify!($x), e);
-                return $ret_val;
+                let message = format!("Failed with {}: {}", stringify!($x), e);
+                return $crate::fail!($ret_val, message, $crate::error_chain!(e));
             }
         }
     };
@@ -32,7 +39,7 @@ macro_rules! ffi_ensure {
     ($check_expr:expr,$ret_value:expr, $message:literal) => {
         if $check_expr {
             ::log::error!("Failed with {}", $message);
-            return $ret_value;
+            return $crate::fail!($ret_value, $message.to_string(), Vec::new());
         }
     };
 }
@@ -60,3 +67,40 @@ macro_rules! match_option {
         }
     };
 }
+
+///Resolves `handle` in the handle `table`, returning provided expression if it's stale
+#[macro_export]
+macro_rules! get_handle {
+    ($table:expr, $handle:expr, $ret_val:expr) => {
+        match $table.get($handle) {
+            Some(a) => a,
+            None => {
+                ::log::error!("Invalid handle {}: {}", stringify!($handle), $handle);
+                let message = format!("Invalid handle {}: {}", stringify!($handle), $handle);
+                return $crate::fail!($ret_val, message, Vec::new());
+            }
+        }
+    };
+}
+
+///Stores last error of the thread, evaluating to the provided exit code
+#[macro_export]
+macro_rules! fail {
+    ($ret_val:expr, $message:expr, $chain:expr) => {{
+        let code: $crate::ExitCode = $ret_val;
+        $crate::last_error::set_last_error(code, $message, $chain);
+        code
+    }};
+}
+
+///Collects messages of the error and its sources, see [`crate::last_error::ErrorChain`]
+#[macro_export]
+macro_rules! error_chain {
+    ($error:expr) => {{
+        #[allow(unused_imports)]
+        use $crate::last_error::{AnyhowChain, DisplayChain, ErrorChain, StdErrorChain};
+        #[allow(clippy::needless_borrow)]
+        let messages = (&&&ErrorChain(&$error)).messages();
+        messages
+    }};
+}
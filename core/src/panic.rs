@@ -0,0 +1,107 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+
+use crate::last_error;
+use crate::ExitCode;
+
+/// Message of the last panic, caught at the FFI boundary
+static LAST_PANIC: Lazy<Mutex<Option<String>>> = Lazy::new(Default::default);
+
+/// Runs body of the exported function, converting panic into [`ExitCode::Panic`].
+/// Last error of the thread is cleared first, so it always describes the latest call
+pub fn catch_panic<F>(f: F) -> ExitCode
+where
+    F: FnOnce() -> ExitCode,
+{
+    last_error::clear_last_error();
+    catch_panic_keeping_error(f)
+}
+
+/// [`catch_panic`] for the functions, which read the last error
+pub fn catch_panic_keeping_error<F>(f: F) -> ExitCode
+where
+    F: FnOnce() -> ExitCode,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(code) => code,
+        Err(e) => {
+            let message = store_panic(e);
+            crate::fail!(ExitCode::Panic, message, Vec::new())
+        }
+    }
+}
+
+/// Awaits `future`, returning message of the panic, if any
+pub async fn catch_panic_async<F>(future: F) -> Result<F::Output, String>
+where
+    F: Future,
+{
+    AssertUnwindSafe(future)
+        .catch_unwind()
+        .await
+        .map_err(store_panic)
+}
+
+/// Returns message of the last caught panic, if any, clearing it
+pub fn take_last_panic() -> Option<String> {
+    match LAST_PANIC.lock() {
+        Ok(mut last) => last.take(),
+        Err(e) => e.into_inner().take(),
+    }
+}
+
+fn store_panic(payload: Box<dyn Any + Send>) -> String {
+    let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown panic".to_string()
+    };
+    log::error!("Caught panic: {}", message);
+
+    match LAST_PANIC.lock() {
+        Ok(mut last) => *last = Some(message.clone()),
+        Err(e) => *e.into_inner() = Some(message.clone()),
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::last_error::take_last_error;
+
+    #[test]
+    fn failed_call_sets_last_error() {
+        let code =
+            catch_panic(|| crate::fail!(ExitCode::BadAddress, "bad".to_string(), Vec::new()));
+        assert!(matches!(code, ExitCode::BadAddress));
+        assert!(matches!(
+            catch_panic_keeping_error(|| ExitCode::Ok),
+            ExitCode::Ok
+        ));
+        assert!(take_last_error().is_some());
+        assert!(take_last_error().is_none());
+    }
+
+    #[test]
+    fn next_call_clears_last_error() {
+        catch_panic(|| crate::fail!(ExitCode::BadAddress, "bad".to_string(), Vec::new()));
+        catch_panic(|| ExitCode::Ok);
+        assert!(take_last_error().is_none());
+    }
+
+    #[test]
+    fn panic_is_caught() {
+        let code = catch_panic(|| panic!("boom"));
+        assert!(matches!(code, ExitCode::Panic));
+        assert!(take_last_error().is_some());
+        assert_eq!(take_last_panic().as_deref(), Some("boom"));
+    }
+}
@@ -0,0 +1,34 @@
+use std::os::raw::{c_char, c_longlong};
+
+use crate::ffi::{new_request, SendPort};
+use crate::global::{OPERATIONS, TRANSPORTS};
+use crate::handles::Handle;
+use crate::panic::catch_panic;
+use crate::{get_handle, ok_or_ret, read_public_key, ExitCode};
+
+/// Looks for wallets of `public_key` of every contract type.
+/// Posts [`crate::ffi::AsyncReply`] with `{"wallets": [..], "failed": [..]}` to `answer_port`:
+/// the deployed or non-empty wallets and those, whose state couldn't be fetched.
+/// Id of the request is written into `request_id`, unless it's null
+#[no_mangle]
+pub unsafe extern "C" fn find_existing_wallets(
+    transport: Handle,
+    public_key: *const c_char,
+    answer_port: c_longlong,
+    request_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        let transport = get_handle!(TRANSPORTS, transport, ExitCode::TransportIsNotInitialized)
+            .inner
+            .clone();
+        let public_key = ok_or_ret!(read_public_key(public_key), ExitCode::InvalidPublicKey);
+
+        let request_id = new_request(request_id);
+        OPERATIONS.spawn_request(
+            "find_existing_wallets",
+            request_id,
+            SendPort::new(answer_port),
+            super::find_existing_wallets(transport, public_key),
+        )
+    })
+}
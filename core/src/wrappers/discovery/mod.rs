@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use ed25519_dalek::PublicKey;
+use futures::future;
+use nekoton::core::ton_wallet::compute_address;
+use nekoton::transport::models::RawContractState;
+use nekoton::transport::Transport;
+use serde::Serialize;
+use ton_block::AccountState;
+
+use crate::ContractType;
+
+mod ffi;
+
+#[derive(Serialize)]
+pub struct ExistingWallet {
+    contract_type: ContractType,
+    address: String,
+    /// Whether the contract code is deployed
+    deployed: bool,
+    /// Balance in nanotons as a decimal string, it may not fit into 64 bits
+    balance: String,
+}
+
+/// Wallet, whose state couldn't be fetched
+#[derive(Serialize)]
+pub struct FailedLookup {
+    contract_type: ContractType,
+    address: String,
+    error: String,
+}
+
+#[derive(Serialize)]
+pub struct Discovery {
+    /// Wallets, which are deployed or have a balance
+    wallets: Vec<ExistingWallet>,
+    failed: Vec<FailedLookup>,
+}
+
+/// Checks wallets of every contract type for `public_key`.
+/// Failure of one lookup doesn't affect the others, it's reported separately
+pub async fn find_existing_wallets(
+    transport: Arc<dyn Transport>,
+    public_key: PublicKey,
+) -> anyhow::Result<Discovery> {
+    let lookups = ContractType::ALL.iter().map(|&contract_type| {
+        let transport = transport.clone();
+        async move {
+            let address = compute_address(&public_key, contract_type.into(), 0);
+            let state = transport.get_contract_state(&address).await;
+            (contract_type, address.to_string(), state)
+        }
+    });
+
+    let mut discovery = Discovery {
+        wallets: Vec::new(),
+        failed: Vec::new(),
+    };
+    for (contract_type, address, state) in future::join_all(lookups).await {
+        match state {
+            Ok(RawContractState::Exists(contract)) => {
+                let deployed = matches!(
+                    contract.account.storage.state,
+                    AccountState::AccountActive(_)
+                );
+                let balance = contract.account.storage.balance.grams.0;
+                if deployed || balance > 0 {
+                    discovery.wallets.push(ExistingWallet {
+                        contract_type,
+                        address,
+                        deployed,
+                        balance: balance.to_string(),
+                    });
+                }
+            }
+            Ok(RawContractState::NotExists) => {}
+            Err(e) => {
+                log::error!("Failed fetching state of {}: {}", address, e);
+                discovery.failed.push(FailedLookup {
+                    contract_type,
+                    address,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+    Ok(discovery)
+}
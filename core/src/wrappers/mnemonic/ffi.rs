@@ -0,0 +1,77 @@
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_uint};
+
+use super::MnemonicKind;
+use crate::panic::catch_panic;
+use crate::{cstr_to_string, ffi_ensure, ok_or_ret, ExitCode};
+
+/// Writes json `{"phrase": .., "public_key": ..}` of a new random phrase into `output`.
+/// `kind` is a [`MnemonicKind`] value
+#[no_mangle]
+pub unsafe extern "C" fn generate_phrase(kind: c_uint, output: *mut *const c_char) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let kind = ok_or_ret!(MnemonicKind::try_from(kind), ExitCode::BadEnumValue);
+        let generated = ok_or_ret!(
+            super::generate_phrase(kind),
+            ExitCode::FailedToGeneratePhrase
+        );
+        let generated = ok_or_ret!(
+            serde_json::to_string(&generated),
+            ExitCode::FailedToGeneratePhrase
+        );
+        let generated = ok_or_ret!(CString::new(generated), ExitCode::FailedToGeneratePhrase);
+        *output = generated.into_raw();
+        ExitCode::Ok
+    })
+}
+
+/// Writes json report of `phrase` validation into `output`. `kind` is a [`MnemonicKind`] value
+#[no_mangle]
+pub unsafe extern "C" fn validate_phrase(
+    phrase: *const c_char,
+    kind: c_uint,
+    output: *mut *const c_char,
+) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(phrase.is_null(), ExitCode::BadPhrase, "Phrase is null");
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let kind = ok_or_ret!(MnemonicKind::try_from(kind), ExitCode::BadEnumValue);
+        let phrase = cstr_to_string!(phrase, ExitCode::BadPhrase);
+        let report = super::validate_phrase(&phrase, kind);
+        let report = ok_or_ret!(serde_json::to_string(&report), ExitCode::BadPhrase);
+        let report = ok_or_ret!(CString::new(report), ExitCode::BadPhrase);
+        *output = report.into_raw();
+        ExitCode::Ok
+    })
+}
+
+/// Writes json list of the dictionary words, starting with `prefix`, into `output`
+#[no_mangle]
+pub unsafe extern "C" fn get_hints(prefix: *const c_char, output: *mut *const c_char) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(prefix.is_null(), ExitCode::BadPhrase, "Prefix is null");
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let prefix = cstr_to_string!(prefix, ExitCode::BadPhrase);
+        let hints = ok_or_ret!(
+            serde_json::to_string(&super::get_hints(&prefix)),
+            ExitCode::BadPhrase
+        );
+        let hints = ok_or_ret!(CString::new(hints), ExitCode::BadPhrase);
+        *output = hints.into_raw();
+        ExitCode::Ok
+    })
+}
@@ -0,0 +1,138 @@
+use std::convert::TryFrom;
+
+use nekoton::crypto::{self, MnemonicType};
+use serde::Serialize;
+
+use crate::NekotonError;
+
+mod ffi;
+
+/// Words count of the legacy TON mnemonic
+const LEGACY_WORDS: usize = 24;
+/// Words count of the BIP39 mnemonic, used by the labs wallets
+const LABS_WORDS: usize = 12;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub enum MnemonicKind {
+    /// 24 words, original TON wallets
+    Legacy = 0,
+    /// 12 words BIP39, TON Labs wallets
+    Labs = 1,
+}
+
+/// Mnemonic kind, passed over FFI as its value
+impl TryFrom<u32> for MnemonicKind {
+    type Error = NekotonError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MnemonicKind::Legacy),
+            1 => Ok(MnemonicKind::Labs),
+            _ => Err(NekotonError::UnknownEnumValue("mnemonic kind", value)),
+        }
+    }
+}
+
+impl MnemonicKind {
+    fn words_count(self) -> usize {
+        match self {
+            MnemonicKind::Legacy => LEGACY_WORDS,
+            MnemonicKind::Labs => LABS_WORDS,
+        }
+    }
+}
+
+impl From<MnemonicKind> for MnemonicType {
+    fn from(kind: MnemonicKind) -> Self {
+        match kind {
+            MnemonicKind::Legacy => MnemonicType::Legacy,
+            MnemonicKind::Labs => MnemonicType::Labs(0),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GeneratedPhrase {
+    phrase: String,
+    /// Hex encoded public key of the first account
+    public_key: String,
+}
+
+pub fn generate_phrase(kind: MnemonicKind) -> Result<GeneratedPhrase, anyhow::Error> {
+    let key = crypto::generate_key(kind.into());
+    let phrase = key.words.join(" ");
+    let keypair = crypto::derive_from_phrase(&phrase, kind.into())?;
+    Ok(GeneratedPhrase {
+        phrase,
+        public_key: hex::encode(keypair.public.as_bytes()),
+    })
+}
+
+#[derive(Serialize)]
+pub struct InvalidWord {
+    index: usize,
+    word: String,
+}
+
+#[derive(Serialize)]
+pub struct PhraseValidation {
+    valid: bool,
+    /// Words, which are not in the dictionary
+    invalid_words: Vec<InvalidWord>,
+    /// Reason if the phrase is invalid as a whole, e.g. wrong words count or checksum
+    error: Option<String>,
+}
+
+/// Checks every word of `phrase` against the dictionary, then the phrase itself.
+/// Words are compared case-insensitively
+pub fn validate_phrase(phrase: &str, kind: MnemonicKind) -> PhraseValidation {
+    let phrase = normalize(phrase);
+    let words: Vec<&str> = phrase.split_whitespace().collect();
+    let invalid_words: Vec<InvalidWord> = words
+        .iter()
+        .enumerate()
+        .filter(|(_, word)| !is_known_word(word))
+        .map(|(index, word)| InvalidWord {
+            index,
+            word: word.to_string(),
+        })
+        .collect();
+
+    let error = if words.len() != kind.words_count() {
+        Some(format!(
+            "Expected {} words, got {}",
+            kind.words_count(),
+            words.len()
+        ))
+    } else if !invalid_words.is_empty() {
+        None
+    } else {
+        crypto::derive_from_phrase(&words.join(" "), kind.into())
+            .err()
+            .map(|e| e.to_string())
+    };
+
+    PhraseValidation {
+        valid: error.is_none() && invalid_words.is_empty(),
+        invalid_words,
+        error,
+    }
+}
+
+fn is_known_word(word: &str) -> bool {
+    let word = normalize(word);
+    crypto::dict::get_hints(&word)
+        .iter()
+        .any(|hint| *hint == word)
+}
+
+/// Dictionary words starting with `prefix`, case-insensitively
+pub fn get_hints(prefix: &str) -> Vec<&'static str> {
+    crypto::dict::get_hints(&normalize(prefix))
+}
+
+/// Dictionary words are lowercase
+fn normalize(text: &str) -> String {
+    text.trim().to_lowercase()
+}
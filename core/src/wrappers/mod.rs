@@ -1,4 +1,9 @@
+mod discovery;
+mod mnemonic;
 pub(crate) mod storage;
 mod ton_wallet;
 
-pub use ton_wallet::{send, SendError, SignData};
+pub use ton_wallet::{
+    send, Confirmations, MultisigTransaction, Outcome, PendingTracker, RetryPolicy, SendError,
+    SignData,
+};
@@ -0,0 +1,563 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use anyhow::Error;
+use ed25519_dalek::PublicKey;
+use hmac::Hmac;
+use nekoton::core::keystore::{KeyStore, KeyStoreEntry};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+
+use super::crypto::{StorageCipher, STORAGE_KEY_LEN};
+use super::schema::Entries;
+use super::{open_storage, KeyStoreWrapper, Namespace, NativeStorage};
+use crate::NekotonError;
+
+/// Version of the backup format. Bump on any change of [`Backup`] or [`BackupContents`]
+const BACKUP_VERSION: u32 = 1;
+const KDF_ITERATIONS: u32 = 100_000;
+/// Max iterations, accepted from the backup, so a crafted one can't stall the import
+const MAX_KDF_ITERATIONS: u32 = 10_000_000;
+const SALT_LEN: usize = 32;
+/// Associated data of the sealed contents
+const BACKUP_AAD: &str = "ntbindings_backup";
+
+/// Password protected backup of the whole keystore
+#[derive(Serialize, Deserialize)]
+struct Backup {
+    version: u32,
+    /// PBKDF2-HMAC-SHA256 iterations
+    iterations: u32,
+    /// Base64 encoded salt
+    salt: String,
+    /// [`BackupContents`] json, sealed with the key derived from the password
+    data: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupContents {
+    version: u32,
+    /// Plaintext keystore namespace of the storage
+    entries: Entries,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum ImportMode {
+    /// Replaces local keystore with the backup, dropping local only keys
+    Replace = 0,
+    /// Only reports what would change
+    DryRun = 1,
+    /// Adds keys, missing locally, keeping local keys and conflicting ones as they are
+    Merge = 2,
+}
+
+/// Import mode, passed over FFI as its value
+impl TryFrom<u32> for ImportMode {
+    type Error = NekotonError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ImportMode::Replace),
+            1 => Ok(ImportMode::DryRun),
+            2 => Ok(ImportMode::Merge),
+            _ => Err(NekotonError::UnknownEnumValue("import mode", value)),
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportReport {
+    /// Whether the backup was written to the keystore
+    applied: bool,
+    /// Keys of the backup, missing locally
+    added: Vec<String>,
+    /// Keys, which are the same in the backup and locally
+    unchanged: Vec<String>,
+    /// Keys with different name or signer in the backup
+    conflicts: Vec<ImportConflict>,
+    /// Local keys, missing in the backup
+    local_only: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImportConflict {
+    public_key: String,
+    local_name: String,
+    backup_name: String,
+}
+
+/// Seals keystore contents with the key derived from `password`, returning base64 encoded backup
+pub async fn export_backup(keystore: &KeyStoreWrapper, password: &str) -> Result<String, Error> {
+    // Keeps keystore consistent with the storage while reading
+    let _keystore = keystore.inner().lock().await;
+    let contents = BackupContents {
+        version: BACKUP_VERSION,
+        entries: keystore.storage().entries().await?,
+    };
+
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let cipher = derive_cipher(password, &salt, KDF_ITERATIONS)?;
+    let backup = Backup {
+        version: BACKUP_VERSION,
+        iterations: KDF_ITERATIONS,
+        salt: base64::encode(&salt),
+        data: cipher.encrypt(BACKUP_AAD, &serde_json::to_string(&contents)?)?,
+    };
+    Ok(base64::encode(serde_json::to_vec(&backup)?))
+}
+
+/// Validates backup and applies it according to `mode`
+pub async fn import_backup(
+    keystore: &KeyStoreWrapper,
+    backup: &str,
+    password: &str,
+    mode: ImportMode,
+) -> Result<ImportReport, Error> {
+    let backup: Backup = serde_json::from_slice(&base64::decode(backup.trim())?)?;
+    if backup.version > BACKUP_VERSION {
+        return Err(BackupError::UnsupportedVersion(backup.version).into());
+    }
+    if backup.iterations == 0 || backup.iterations > MAX_KDF_ITERATIONS {
+        return Err(BackupError::InvalidIterations(backup.iterations).into());
+    }
+    let cipher = derive_cipher(password, &base64::decode(&backup.salt)?, backup.iterations)?;
+    let contents = cipher
+        .decrypt(BACKUP_AAD, &backup.data)
+        .map_err(|_| BackupError::InvalidPassword)?;
+    let contents: BackupContents = serde_json::from_str(&contents)?;
+
+    let (imported_storage, mut imported) = open_detached(contents.entries.clone()).await?;
+
+    let mut inner = keystore.inner().lock().await;
+    let mut report = compare(inner.get_entries().await, imported.get_entries().await);
+
+    match mode {
+        ImportMode::DryRun => return Ok(report),
+        ImportMode::Replace => {
+            keystore.storage().replace_entries(contents.entries).await?;
+        }
+        ImportMode::Merge => {
+            // Local keys win, so the backup keeps only keys, missing locally
+            let existing = report
+                .unchanged
+                .iter()
+                .chain(report.conflicts.iter().map(|conflict| &conflict.public_key));
+            for public_key in existing {
+                imported
+                    .remove_key(&PublicKey::from_bytes(&hex::decode(public_key)?)?)
+                    .await?;
+            }
+            let mut entries = keystore.storage().entries().await?;
+            merge_entries(&mut entries, imported_storage.entries().await?);
+
+            // Checks the merge before writing anything: the keystore must have
+            // every local key and every added one, nothing else
+            let mut expected: Vec<String> = inner
+                .get_entries()
+                .await
+                .into_iter()
+                .map(|entry| hex::encode(entry.public_key.as_bytes()))
+                .chain(report.added.iter().cloned())
+                .collect();
+            let (_, merged) = open_detached(entries.clone()).await?;
+            let mut merged: Vec<String> = merged
+                .get_entries()
+                .await
+                .into_iter()
+                .map(|entry| hex::encode(entry.public_key.as_bytes()))
+                .collect();
+            expected.sort();
+            merged.sort();
+            if merged != expected {
+                return Err(BackupError::MergeFailed.into());
+            }
+            keystore.storage().replace_entries(entries).await?;
+        }
+    }
+    *inner = open_storage(keystore.storage().clone()).await?;
+    report.applied = true;
+    Ok(report)
+}
+
+/// Loads `entries` into a separate in-memory keystore, so broken ones never reach the storage
+async fn open_detached(entries: Entries) -> Result<(NativeStorage, KeyStore), Error> {
+    let storage = NativeStorage::new("{}", None, false)?.with_namespace(Namespace::Keystore);
+    storage.replace_entries(entries).await?;
+    let keystore = open_storage(storage.clone())
+        .await
+        .map_err(|e| BackupError::InvalidContents(e.to_string()))?;
+    Ok((storage, keystore))
+}
+
+/// Adds `backup` entries to `local` ones. Entries of both are merged as json, keeping
+/// local values, so keys of the same signer, e.g. accounts of one master key, are joined
+fn merge_entries(local: &mut Entries, backup: Entries) {
+    for (key, value) in backup {
+        match local.get_mut(&key) {
+            Some(existing) => *existing = merge_json(existing, &value),
+            None => {
+                local.insert(key, value);
+            }
+        }
+    }
+}
+
+fn merge_json(local: &str, backup: &str) -> String {
+    match (
+        serde_json::from_str::<Value>(local),
+        serde_json::from_str::<Value>(backup),
+    ) {
+        (Ok(mut merged @ Value::Object(_)), Ok(backup))
+        | (Ok(mut merged @ Value::Array(_)), Ok(backup)) => {
+            merge_value(&mut merged, backup);
+            merged.to_string()
+        }
+        _ => local.to_owned(),
+    }
+}
+
+fn merge_value(local: &mut Value, backup: Value) {
+    match (local, backup) {
+        (Value::Object(local), Value::Object(backup)) => {
+            for (key, value) in backup {
+                match local.get_mut(&key) {
+                    Some(existing) => merge_value(existing, value),
+                    None => {
+                        local.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(local), Value::Array(backup)) => {
+            for item in backup {
+                merge_item(local, item);
+            }
+        }
+        // Signers store their state as json strings
+        (Value::String(local), Value::String(backup)) => *local = merge_json(local, &backup),
+        _ => {}
+    }
+}
+
+/// Maps with non string keys are serialized as arrays of `[key, value]` pairs,
+/// so pairs with the same key are merged instead of being added twice
+fn merge_item(local: &mut Vec<Value>, mut item: Value) {
+    let position = pair_key(&item).and_then(|key| {
+        local
+            .iter()
+            .position(|existing| pair_key(existing) == Some(key))
+    });
+    match position {
+        Some(i) => merge_value(&mut local[i][1], item[1].take()),
+        None if !local.contains(&item) => local.push(item),
+        None => {}
+    }
+}
+
+fn pair_key(item: &Value) -> Option<&Value> {
+    match item {
+        Value::Array(pair) if pair.len() == 2 => Some(&pair[0]),
+        _ => None,
+    }
+}
+
+fn compare(local: Vec<KeyStoreEntry>, imported: Vec<KeyStoreEntry>) -> ImportReport {
+    let mut local: HashMap<String, KeyStoreEntry> = local
+        .into_iter()
+        .map(|entry| (hex::encode(entry.public_key.as_bytes()), entry))
+        .collect();
+
+    let mut report = ImportReport::default();
+    for entry in imported {
+        let public_key = hex::encode(entry.public_key.as_bytes());
+        match local.remove(&public_key) {
+            None => report.added.push(public_key),
+            Some(existing)
+                if existing.name == entry.name && existing.signer_name == entry.signer_name =>
+            {
+                report.unchanged.push(public_key)
+            }
+            Some(existing) => report.conflicts.push(ImportConflict {
+                public_key,
+                local_name: existing.name,
+                backup_name: entry.name,
+            }),
+        }
+    }
+    report.local_only = local
+        .into_iter()
+        .map(|(public_key, _)| public_key)
+        .collect();
+    report
+}
+
+fn derive_cipher(password: &str, salt: &[u8], iterations: u32) -> Result<StorageCipher, Error> {
+    let mut key = [0u8; STORAGE_KEY_LEN];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, iterations, &mut key);
+    StorageCipher::new(&key)
+}
+
+#[derive(thiserror::Error, Debug)]
+enum BackupError {
+    #[error("Backup version {0} is newer than supported")]
+    UnsupportedVersion(u32),
+    #[error("Invalid backup password")]
+    InvalidPassword,
+    #[error("Invalid backup contents: {0}")]
+    InvalidContents(String),
+    #[error("Invalid number of KDF iterations: {0}")]
+    InvalidIterations(u32),
+    #[error("Backup keys can't be merged with the local ones")]
+    MergeFailed,
+}
+
+#[cfg(test)]
+mod tests {
+    use nekoton::crypto::{
+        self, DerivedKeyCreateInput, DerivedKeySigner, EncryptedKeyCreateInput, EncryptedKeySigner,
+        MnemonicType,
+    };
+
+    use super::*;
+
+    const KEY_PASSWORD: &str = "key password";
+
+    async fn keystore() -> KeyStoreWrapper {
+        let storage = NativeStorage::new("{}", None, false).unwrap();
+        KeyStoreWrapper::open(storage).await.unwrap()
+    }
+
+    fn phrase() -> String {
+        crypto::generate_key(MnemonicType::Labs(0)).words.join(" ")
+    }
+
+    /// Adds encrypted key, returning its hex public key
+    async fn add_encrypted(keystore: &KeyStoreWrapper, name: &str, phrase: &str) -> String {
+        let input = EncryptedKeyCreateInput {
+            phrase: phrase.into(),
+            mnemonic_type: MnemonicType::Labs(0),
+            password: KEY_PASSWORD.into(),
+        };
+        let mut inner = keystore.inner().lock().await;
+        let entry = inner
+            .add_key::<EncryptedKeySigner>(name, input)
+            .await
+            .unwrap();
+        hex::encode(entry.public_key.as_bytes())
+    }
+
+    /// Imports master key with the first account and derives `accounts` more,
+    /// returning hex public keys of every account
+    async fn add_derived(keystore: &KeyStoreWrapper, phrase: &str, accounts: u16) -> Vec<String> {
+        let mut inner = keystore.inner().lock().await;
+        let master = inner
+            .add_key::<DerivedKeySigner>(
+                "master",
+                DerivedKeyCreateInput::Import {
+                    phrase: phrase.into(),
+                    password: KEY_PASSWORD.into(),
+                },
+            )
+            .await
+            .unwrap();
+        let mut public_keys = vec![hex::encode(master.public_key.as_bytes())];
+        for account_id in 1..=accounts {
+            let entry = inner
+                .add_key::<DerivedKeySigner>(
+                    &format!("account {}", account_id),
+                    DerivedKeyCreateInput::Derive {
+                        master_key: master.master_key,
+                        account_id,
+                        password: KEY_PASSWORD.into(),
+                    },
+                )
+                .await
+                .unwrap();
+            public_keys.push(hex::encode(entry.public_key.as_bytes()));
+        }
+        public_keys
+    }
+
+    /// Sorted hex public keys with names
+    async fn keys(keystore: &KeyStoreWrapper) -> Vec<(String, String)> {
+        let mut keys: Vec<(String, String)> = keystore
+            .inner()
+            .lock()
+            .await
+            .get_entries()
+            .await
+            .into_iter()
+            .map(|entry| (hex::encode(entry.public_key.as_bytes()), entry.name))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn sorted(mut keys: Vec<String>) -> Vec<String> {
+        keys.sort();
+        keys
+    }
+
+    fn backup_error(error: Error) -> BackupError {
+        error.downcast().unwrap()
+    }
+
+    /// Backup of the empty keystore with custom header
+    fn forge(version: u32, iterations: u32) -> String {
+        let backup = Backup {
+            version,
+            iterations,
+            salt: base64::encode(&[0; SALT_LEN]),
+            data: String::new(),
+        };
+        base64::encode(serde_json::to_vec(&backup).unwrap())
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let keystore = keystore().await;
+        let backup = export_backup(&keystore, "password").await.unwrap();
+
+        let report = import_backup(&keystore, &backup, "password", ImportMode::DryRun)
+            .await
+            .unwrap();
+        assert!(!report.applied);
+        let report = import_backup(&keystore, &backup, "password", ImportMode::Replace)
+            .await
+            .unwrap();
+        assert!(report.applied);
+        assert!(report.added.is_empty() && report.conflicts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn roundtrip_with_keys() {
+        let source = keystore().await;
+        let mut public_keys = add_derived(&source, &phrase(), 2).await;
+        public_keys.push(add_encrypted(&source, "encrypted", &phrase()).await);
+        let backup = export_backup(&source, "password").await.unwrap();
+
+        let target = keystore().await;
+        let report = import_backup(&target, &backup, "password", ImportMode::Replace)
+            .await
+            .unwrap();
+        assert!(report.applied);
+        assert_eq!(sorted(report.added), sorted(public_keys));
+        assert!(report.conflicts.is_empty() && report.local_only.is_empty());
+        assert_eq!(keys(&target).await, keys(&source).await);
+    }
+
+    #[tokio::test]
+    async fn merge_keeps_local_keys() {
+        let source = keystore().await;
+        let mut added = add_derived(&source, &phrase(), 1).await;
+        added.push(add_encrypted(&source, "encrypted", &phrase()).await);
+        let backup = export_backup(&source, "password").await.unwrap();
+
+        let target = keystore().await;
+        let local = add_encrypted(&target, "local", &phrase()).await;
+        let report = import_backup(&target, &backup, "password", ImportMode::Merge)
+            .await
+            .unwrap();
+        assert!(report.applied);
+        assert_eq!(sorted(report.added.clone()), sorted(added.clone()));
+        assert_eq!(report.local_only, vec![local.clone()]);
+
+        let mut expected = keys(&source).await;
+        expected.push((local, "local".to_owned()));
+        expected.sort();
+        assert_eq!(keys(&target).await, expected);
+    }
+
+    #[tokio::test]
+    async fn merge_reports_conflicts() {
+        let derived_phrase = phrase();
+        let encrypted_phrase = phrase();
+        let source = keystore().await;
+        let accounts = add_derived(&source, &derived_phrase, 1).await;
+        let encrypted = add_encrypted(&source, "backup", &encrypted_phrase).await;
+        let backup = export_backup(&source, "password").await.unwrap();
+
+        // Same keys locally: the master key without the second account and the renamed key
+        let target = keystore().await;
+        add_derived(&target, &derived_phrase, 0).await;
+        add_encrypted(&target, "renamed", &encrypted_phrase).await;
+        let report = import_backup(&target, &backup, "password", ImportMode::Merge)
+            .await
+            .unwrap();
+        assert!(report.applied);
+        assert_eq!(report.added, vec![accounts[1].clone()]);
+        assert_eq!(report.unchanged, vec![accounts[0].clone()]);
+        assert_eq!(report.conflicts.len(), 1);
+        let conflict = &report.conflicts[0];
+        assert_eq!(conflict.public_key, encrypted);
+        assert_eq!(conflict.local_name, "renamed");
+        assert_eq!(conflict.backup_name, "backup");
+
+        // Conflicting key is not overwritten, the account joins the local master key
+        let keys = keys(&target).await;
+        assert_eq!(keys.len(), 3);
+        assert!(keys.contains(&(encrypted, "renamed".to_owned())));
+        assert!(keys.contains(&(accounts[1].clone(), "account 1".to_owned())));
+    }
+
+    #[tokio::test]
+    async fn dry_run_changes_nothing() {
+        let source = keystore().await;
+        add_encrypted(&source, "encrypted", &phrase()).await;
+        let backup = export_backup(&source, "password").await.unwrap();
+
+        let target = keystore().await;
+        let report = import_backup(&target, &backup, "password", ImportMode::DryRun)
+            .await
+            .unwrap();
+        assert!(!report.applied);
+        assert_eq!(report.added.len(), 1);
+        assert!(keys(&target).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_password() {
+        let keystore = keystore().await;
+        let backup = export_backup(&keystore, "password").await.unwrap();
+
+        let error = import_backup(&keystore, &backup, "wrong", ImportMode::Replace)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(backup_error(error), BackupError::InvalidPassword));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_iterations() {
+        let keystore = keystore().await;
+        for iterations in [0, MAX_KDF_ITERATIONS + 1].iter().copied() {
+            let backup = forge(BACKUP_VERSION, iterations);
+            let error = import_backup(&keystore, &backup, "password", ImportMode::DryRun)
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(
+                backup_error(error),
+                BackupError::InvalidIterations(n) if n == iterations
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_newer_version() {
+        let keystore = keystore().await;
+        let backup = forge(BACKUP_VERSION + 1, KDF_ITERATIONS);
+        let error = import_backup(&keystore, &backup, "password", ImportMode::DryRun)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            backup_error(error),
+            BackupError::UnsupportedVersion(_)
+        ));
+    }
+}
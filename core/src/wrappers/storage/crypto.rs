@@ -0,0 +1,120 @@
+use anyhow::Error;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+
+use super::schema::Encryption;
+
+/// Header of the encrypted value. Bump on any change of the envelope format.
+const HEADER_V1: &str = "nt1:";
+/// Version of the envelope format, recorded in the document header
+const ENCRYPTION_VERSION: u32 = 1;
+/// Host supplied key is used as is
+const KDF: &str = "none";
+const AEAD: &str = "chacha20poly1305";
+const NONCE_LEN: usize = 12;
+pub const STORAGE_KEY_LEN: usize = 32;
+
+/// Encrypts storage values with a host supplied key.
+///
+/// Every value is sealed separately as `nt1:<base64(nonce || ciphertext)>`.
+/// Whether the values are sealed is recorded once, in [`Encryption`] header of the document.
+/// Associated data binds the value to its location, e.g. storage namespace and key name,
+/// so values can't be swapped between keys.
+#[derive(Clone)]
+pub struct StorageCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl StorageCipher {
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        if key.len() != STORAGE_KEY_LEN {
+            return Err(StorageCipherError::InvalidKeyLength.into());
+        }
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        })
+    }
+
+    /// Parses hex encoded 32 byte key
+    pub fn from_hex(key: &str) -> Result<Self, Error> {
+        Self::new(&hex::decode(key)?)
+    }
+
+    /// Header of the document, encrypted by the cipher
+    pub fn encryption() -> Encryption {
+        Encryption {
+            version: ENCRYPTION_VERSION,
+            kdf: KDF.to_string(),
+            aead: AEAD.to_string(),
+        }
+    }
+
+    /// Checks that the document with `encryption` header can be decrypted by the cipher
+    pub fn check(encryption: &Encryption) -> Result<(), Error> {
+        if *encryption == Self::encryption() {
+            Ok(())
+        } else {
+            Err(StorageCipherError::UnsupportedEncryption.into())
+        }
+    }
+
+    pub fn encrypt(&self, aad: &str, value: &str) -> Result<String, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(
+                    Nonce::from_slice(&nonce),
+                    Payload {
+                        msg: value.as_bytes(),
+                        aad: aad.as_bytes(),
+                    },
+                )
+                .map_err(|_| StorageCipherError::FailedToEncrypt)?,
+        );
+        Ok(format!("{}{}", HEADER_V1, base64::encode(sealed)))
+    }
+
+    pub fn decrypt(&self, aad: &str, value: &str) -> Result<String, Error> {
+        let sealed = match value.strip_prefix(HEADER_V1) {
+            Some(sealed) => base64::decode(sealed)?,
+            None => return Err(StorageCipherError::UnknownFormat.into()),
+        };
+        if sealed.len() < NONCE_LEN {
+            return Err(StorageCipherError::UnknownFormat.into());
+        }
+        let (nonce, data) = sealed.split_at(NONCE_LEN);
+        let value = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: data,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| StorageCipherError::FailedToDecrypt)?;
+        Ok(String::from_utf8(value)?)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum StorageCipherError {
+    #[error("Storage key must be 32 bytes long")]
+    InvalidKeyLength,
+    #[error("Unknown encrypted value format")]
+    UnknownFormat,
+    #[error("Failed to encrypt value")]
+    FailedToEncrypt,
+    #[error("Failed to decrypt value. Wrong storage key?")]
+    FailedToDecrypt,
+    #[error("Storage is encrypted, but no key provided")]
+    KeyRequired,
+    #[error("Plaintext storage can't be opened with a key")]
+    UnexpectedPlaintext,
+    #[error("Storage is encrypted in unsupported way")]
+    UnsupportedEncryption,
+}
@@ -0,0 +1,150 @@
+use anyhow::Error;
+use ed25519_dalek::PublicKey;
+use nekoton::core::keystore::KeyStore;
+use nekoton::core::ton_wallet::compute_address;
+use nekoton::crypto::{
+    self, DerivedKeyCreateInput, DerivedKeyExportParams, DerivedKeySigner, MnemonicType,
+};
+use serde::{Deserialize, Serialize};
+
+use super::entries::KeyOrder;
+use crate::ContractType;
+
+/// Max number of accounts derived by a single call
+const MAX_ACCOUNTS: u16 = 100;
+
+#[derive(Deserialize)]
+pub struct DeriveAccountsInput {
+    /// Hex encoded public key of the master key
+    master_key: String,
+    password: String,
+    /// First account index
+    from: u16,
+    /// Last account index, inclusive
+    to: u16,
+}
+
+#[derive(Serialize)]
+pub struct DerivedAccount {
+    account_id: u16,
+    /// Hex encoded public key
+    public_key: String,
+    addresses: Vec<DerivedAddress>,
+}
+
+#[derive(Serialize)]
+pub struct DerivedAddress {
+    contract_type: ContractType,
+    address: String,
+}
+
+#[derive(Deserialize)]
+pub struct AddAccountsInput {
+    /// Hex encoded public key of the master key
+    master_key: String,
+    password: String,
+    accounts: Vec<AccountToAdd>,
+}
+
+#[derive(Deserialize)]
+pub struct AccountToAdd {
+    account_id: u16,
+    name: String,
+}
+
+/// Derives accounts `from..=to` of the master key, computing their addresses for every contract type
+pub async fn derive_accounts(
+    keystore: &KeyStore,
+    input: DeriveAccountsInput,
+) -> Result<Vec<DerivedAccount>, Error> {
+    if input.from > input.to || input.to - input.from >= MAX_ACCOUNTS {
+        return Err(DeriveError::InvalidRange.into());
+    }
+    let master_key = read_key(&input.master_key)?;
+    let exported = keystore
+        .export_key::<DerivedKeySigner>(DerivedKeyExportParams {
+            master_key,
+            password: input.password.into(),
+        })
+        .await?;
+    // Phrase is borrowed from the secure string, which is zeroized once `exported` is dropped
+    let phrase = exported.phrase.unsecure();
+
+    (input.from..=input.to)
+        .map(|account_id| {
+            let keypair = crypto::derive_from_phrase(phrase, MnemonicType::Labs(account_id))?;
+            let addresses = ContractType::ALL
+                .iter()
+                .map(|&contract_type| DerivedAddress {
+                    contract_type,
+                    address: compute_address(&keypair.public, contract_type.into(), 0).to_string(),
+                })
+                .collect();
+            Ok(DerivedAccount {
+                account_id,
+                public_key: hex::encode(keypair.public.as_bytes()),
+                addresses,
+            })
+        })
+        .collect()
+}
+
+/// Adds selected accounts of the master key to the keystore.
+/// Either every account is added, or none: accounts added before a failure are removed
+pub async fn add_accounts(
+    keystore: &mut KeyStore,
+    order: &KeyOrder,
+    input: AddAccountsInput,
+) -> Result<(), Error> {
+    let master_key = read_key(&input.master_key)?;
+    let mut added = Vec::with_capacity(input.accounts.len());
+    for account in input.accounts {
+        let result = keystore
+            .add_key::<DerivedKeySigner>(
+                &account.name,
+                DerivedKeyCreateInput::Derive {
+                    master_key,
+                    account_id: account.account_id,
+                    password: input.password.clone().into(),
+                },
+            )
+            .await;
+        let public_key = match result {
+            Ok(entry) => entry.public_key,
+            Err(e) => {
+                rollback(keystore, order, &added).await;
+                return Err(DeriveError::FailedToAddAccount(account.account_id, e).into());
+            }
+        };
+        added.push(public_key);
+        if let Err(e) = order.push(&public_key).await {
+            rollback(keystore, order, &added).await;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Removes keys added by the failed `add_accounts`
+async fn rollback(keystore: &mut KeyStore, order: &KeyOrder, added: &[PublicKey]) {
+    for public_key in added {
+        if let Err(e) = keystore.remove_key(public_key).await {
+            log::error!("Failed removing partially added account: {}", e);
+        }
+        if let Err(e) = order.remove(public_key).await {
+            log::error!("Failed removing partially added account from order: {}", e);
+        }
+    }
+}
+
+fn read_key(key: &str) -> Result<PublicKey, Error> {
+    Ok(PublicKey::from_bytes(&hex::decode(key)?)?)
+}
+
+#[derive(thiserror::Error, Debug)]
+enum DeriveError {
+    #[error("Invalid accounts range")]
+    InvalidRange,
+    #[error("Failed to add account {0}: {1}")]
+    FailedToAddAccount(u16, Error),
+}
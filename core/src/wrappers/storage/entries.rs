@@ -0,0 +1,116 @@
+use anyhow::Error;
+use ed25519_dalek::PublicKey;
+use nekoton::core::keystore::KeyStoreEntry;
+use nekoton::external::Storage;
+use serde::Serialize;
+
+use super::{NativeStorage, DERIVED_SIGNER};
+
+/// Keystore namespace key of the hex public keys list, in order of addition
+const KEY_ORDER: &str = "__ntbindings__key_order";
+
+#[derive(Serialize)]
+pub struct KeyEntry {
+    /// Hex encoded public key
+    public_key: String,
+    /// Name of the signer, `encrypted` or `derived`
+    signer: String,
+    name: String,
+    /// Hex encoded master key, derived keys only
+    master_key: Option<String>,
+    /// Account index under the master key, derived keys only
+    account_id: Option<u16>,
+    /// Position in order of creation
+    order: usize,
+}
+
+impl KeyEntry {
+    fn new(entry: KeyStoreEntry, order: usize) -> Self {
+        let derived = entry.signer_name == DERIVED_SIGNER;
+        Self {
+            public_key: hex::encode(entry.public_key.as_bytes()),
+            name: entry.name,
+            master_key: if derived {
+                Some(hex::encode(entry.master_key.as_bytes()))
+            } else {
+                None
+            },
+            account_id: if derived {
+                Some(entry.account_id)
+            } else {
+                None
+            },
+            signer: entry.signer_name,
+            order,
+        }
+    }
+}
+
+/// Tracks order of the keystore entries, which keystore itself doesn't keep
+#[derive(Clone)]
+pub struct KeyOrder {
+    storage: NativeStorage,
+}
+
+impl KeyOrder {
+    pub fn new(storage: NativeStorage) -> Self {
+        Self { storage }
+    }
+
+    async fn load(&self) -> Result<Vec<String>, Error> {
+        Ok(match self.storage.get(KEY_ORDER).await? {
+            Some(data) => serde_json::from_str(&data)?,
+            None => Vec::new(),
+        })
+    }
+
+    async fn save(&self, order: &[String]) -> Result<(), Error> {
+        self.storage
+            .set(KEY_ORDER, &serde_json::to_string(order)?)
+            .await
+    }
+
+    pub async fn push(&self, public_key: &PublicKey) -> Result<(), Error> {
+        let public_key = hex::encode(public_key.as_bytes());
+        let mut order = self.load().await?;
+        if !order.contains(&public_key) {
+            order.push(public_key);
+            self.save(&order).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove(&self, public_key: &PublicKey) -> Result<(), Error> {
+        let public_key = hex::encode(public_key.as_bytes());
+        let mut order = self.load().await?;
+        order.retain(|key| key != &public_key);
+        self.save(&order).await
+    }
+
+    pub async fn clear(&self) -> Result<(), Error> {
+        self.storage.remove(KEY_ORDER).await
+    }
+
+    /// Sorts `entries` in order of creation, without writing anything.
+    /// Keys added without tracking go last, ordered by public key
+    pub async fn sort(&self, entries: Vec<KeyStoreEntry>) -> Result<Vec<KeyEntry>, Error> {
+        let order = self.load().await?;
+        let mut entries: Vec<(String, KeyStoreEntry)> = entries
+            .into_iter()
+            .map(|entry| (hex::encode(entry.public_key.as_bytes()), entry))
+            .collect();
+        entries.sort_by(|(left, _), (right, _)| left.cmp(right));
+        // Stable sort keeps untracked keys ordered by public key
+        entries.sort_by_key(|(public_key, _)| {
+            order
+                .iter()
+                .position(|key| key == public_key)
+                .unwrap_or(usize::MAX)
+        });
+        Ok(entries
+            .into_iter()
+            .enumerate()
+            .map(|(i, (_, entry))| KeyEntry::new(entry, i))
+            .collect())
+    }
+}
@@ -1,149 +1,647 @@
-use std::os::raw::c_char;
+use std::convert::TryFrom;
+use std::os::raw::{c_char, c_longlong, c_uint};
+use std::time::Duration;
 
-use nekoton::core::keystore::KeyStore;
-
-use super::open_storage;
-use crate::utils::ffi_mut_cast;
+use super::backup::{self, ImportMode};
+use super::crypto::StorageCipherError;
+use super::derive::{self, AddAccountsInput, DeriveAccountsInput};
+use super::schema::SchemaError;
+use super::signature::{self, SignInput, VerifyInput};
+use super::{Namespace, NativeStorage, StorageCipher};
+use crate::ffi::{next_request_id, SendPort};
+use crate::global::{CONTEXTS, KEYSTORES, OPERATIONS, STORAGES};
+use crate::handles::Handle;
+use crate::panic::catch_panic;
 use crate::wrappers::storage::models::{
     CreateKeyData, ExportKeyData, KeyStoreWrapper, UpdateKeyData,
 };
-use crate::{cstr_to_string, get_runtime, ok_or_ret, ExitCode};
-use crate::{ffi_ensure, read_public_key};
+use crate::wrappers::SignData;
+use crate::{cstr_to_string, get_handle, get_runtime, ok_or_ret, ExitCode};
+use crate::{fail, ffi_ensure, read_public_key, NekotonError};
 use nekoton::crypto::{DerivedKeySigner, EncryptedKeySigner};
-use std::ffi::CString;
+use nekoton::external::Storage;
+use std::ffi::{CStr, CString};
+
+/// Writes json dump of `storage` into `output`
+#[no_mangle]
+pub unsafe extern "C" fn dump_storage(storage: Handle, output: *mut *const c_char) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let storage = get_handle!(STORAGES, storage, ExitCode::StorageIsNotInitialized);
+        let dump = ok_or_ret!(
+            get_runtime!().block_on(storage.dump()),
+            ExitCode::FailedToDumpStorage
+        );
+        let dump = ok_or_ret!(CString::new(dump), ExitCode::FailedToDumpStorage);
+        *output = dump.into_raw();
+        ExitCode::Ok
+    })
+}
+
+/// Subscribes `port` to storage changes. Each change is posted as json
+/// `{"namespace": "keystore" | "wallet_cache" | "app_settings", "key": .., "old": <was present>,
+/// "new": <is present>}`. Pass `0` to unsubscribe.
+/// Failed background writes of the keystore are posted as `{"namespace": .., "key": .., "error": ..}`
+#[no_mangle]
+pub unsafe extern "C" fn set_storage_listener(storage: Handle, port: c_longlong) -> ExitCode {
+    catch_panic(|| {
+        let port = if port == 0 {
+            None
+        } else {
+            Some(SendPort::new(port))
+        };
+        let storage = get_handle!(STORAGES, storage, ExitCode::StorageIsNotInitialized);
+        storage.set_listener(port);
+        ExitCode::Ok
+    })
+}
+
+/// Re-encrypts every value of `storage` with hex encoded 32 byte `new_key`.
+/// Null `new_key` stores values in plaintext.
+#[no_mangle]
+pub unsafe extern "C" fn rekey_storage(storage: Handle, new_key: *const c_char) -> ExitCode {
+    catch_panic(|| {
+        let cipher = ok_or_ret!(read_storage_key(new_key), ExitCode::BadStorageKey);
+        let storage = get_handle!(STORAGES, storage, ExitCode::StorageIsNotInitialized);
+        ok_or_ret!(
+            get_runtime!().block_on(storage.rekey(cipher)),
+            ExitCode::FailedToRekeyStorage
+        );
+        ExitCode::Ok
+    })
+}
 
-pub async unsafe fn create_keystore(data: *mut c_char) -> Result<KeyStore, ExitCode> {
-    if data.is_null() {
-        return Err(ExitCode::BadKeystoreData);
+/// Writes value of `key` from `namespace` of `storage` into `output`, null if there is no such key.
+/// `namespace` is a [`Namespace`] value. The string must be freed with `free_cstring`
+#[no_mangle]
+pub unsafe extern "C" fn get_storage_value(
+    storage: Handle,
+    namespace: c_uint,
+    key: *const c_char,
+    output: *mut *const c_char,
+) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let storage = get_handle!(STORAGES, storage, ExitCode::StorageIsNotInitialized);
+        let namespace = ok_or_ret!(Namespace::try_from(namespace), ExitCode::BadEnumValue);
+        let storage = ok_or_ret!(
+            app_storage(&storage, namespace),
+            ExitCode::ReservedNamespace
+        );
+        let key = cstr_to_string!(key, ExitCode::FailedToAccessStorage);
+        let value = ok_or_ret!(
+            get_runtime!().block_on(storage.get(&key)),
+            ExitCode::FailedToAccessStorage
+        );
+        *output = match value {
+            Some(value) => {
+                ok_or_ret!(CString::new(value), ExitCode::FailedToAccessStorage).into_raw()
+            }
+            None => std::ptr::null(),
+        };
+        ExitCode::Ok
+    })
+}
+
+/// Writes `value` of `key` into `namespace` of `storage`, see [`get_storage_value`]
+#[no_mangle]
+pub unsafe extern "C" fn set_storage_value(
+    storage: Handle,
+    namespace: c_uint,
+    key: *const c_char,
+    value: *const c_char,
+) -> ExitCode {
+    catch_panic(|| {
+        let storage = get_handle!(STORAGES, storage, ExitCode::StorageIsNotInitialized);
+        let namespace = ok_or_ret!(Namespace::try_from(namespace), ExitCode::BadEnumValue);
+        let storage = ok_or_ret!(
+            app_storage(&storage, namespace),
+            ExitCode::ReservedNamespace
+        );
+        let key = cstr_to_string!(key, ExitCode::FailedToAccessStorage);
+        let value = cstr_to_string!(value, ExitCode::FailedToAccessStorage);
+        ok_or_ret!(
+            get_runtime!().block_on(storage.set(&key, &value)),
+            ExitCode::FailedToAccessStorage
+        );
+        ExitCode::Ok
+    })
+}
+
+/// Removes `key` from `namespace` of `storage`, see [`get_storage_value`]. Missing key is not an error
+#[no_mangle]
+pub unsafe extern "C" fn remove_storage_value(
+    storage: Handle,
+    namespace: c_uint,
+    key: *const c_char,
+) -> ExitCode {
+    catch_panic(|| {
+        let storage = get_handle!(STORAGES, storage, ExitCode::StorageIsNotInitialized);
+        let namespace = ok_or_ret!(Namespace::try_from(namespace), ExitCode::BadEnumValue);
+        let storage = ok_or_ret!(
+            app_storage(&storage, namespace),
+            ExitCode::ReservedNamespace
+        );
+        let key = cstr_to_string!(key, ExitCode::FailedToAccessStorage);
+        ok_or_ret!(
+            get_runtime!().block_on(storage.remove(&key)),
+            ExitCode::FailedToAccessStorage
+        );
+        ExitCode::Ok
+    })
+}
+
+/// Storage over `namespace`, which the app may access directly.
+/// The keystore namespace is written by the keystore only
+fn app_storage(
+    storage: &NativeStorage,
+    namespace: Namespace,
+) -> Result<NativeStorage, SchemaError> {
+    match namespace {
+        Namespace::Keystore => Err(SchemaError::ReservedNamespace),
+        Namespace::WalletCache | Namespace::AppSettings => Ok(storage.with_namespace(namespace)),
+    }
+}
+
+/// Opens keystore over `storage`, writing its handle into `keystore_handle`.
+/// The handle must be released with [`delete_keystore`]
+#[no_mangle]
+pub unsafe extern "C" fn open_keystore(storage: Handle, keystore_handle: *mut Handle) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            keystore_handle.is_null(),
+            ExitCode::NullOutputPointer,
+            "Keystore output is null"
+        );
+        let storage = get_handle!(STORAGES, storage, ExitCode::StorageIsNotInitialized);
+        let keystore = ok_or_ret!(
+            get_runtime!().block_on(KeyStoreWrapper::open((*storage).clone())),
+            ExitCode::FailedToCreateKeystore
+        );
+        *keystore_handle = KEYSTORES.insert(keystore);
+        ExitCode::Ok
+    })
+}
+
+/// Writes handle of the keystore, used by `context`, into `keystore_handle`.
+/// The handle stays valid after the context is deleted and must be released with [`delete_keystore`]
+#[no_mangle]
+pub unsafe extern "C" fn get_keystore(context: Handle, keystore_handle: *mut Handle) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            keystore_handle.is_null(),
+            ExitCode::NullOutputPointer,
+            "Keystore output is null"
+        );
+        let context = get_handle!(CONTEXTS, context, ExitCode::NoContextProvided);
+        *keystore_handle = KEYSTORES.insert(context.keystore.clone());
+        ExitCode::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn delete_keystore(keystore: Handle) -> ExitCode {
+    catch_panic(|| match KEYSTORES.remove(keystore) {
+        Some(_) => ExitCode::Ok,
+        None => fail!(
+            ExitCode::BadKeystoreData,
+            format!("Invalid handle keystore: {}", keystore),
+            Vec::new()
+        ),
+    })
+}
+
+pub async unsafe fn create_native_storage(
+    data: *const c_char,
+    path: *const c_char,
+    key: *const c_char,
+    seal_plaintext: bool,
+) -> anyhow::Result<NativeStorage> {
+    let cipher = read_storage_key(key)?;
+    let data = if data.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(data).to_str()?)
+    };
+    if path.is_null() {
+        return match data {
+            Some(data) => NativeStorage::new(data, cipher, seal_plaintext),
+            None => Err(NekotonError::NullPointerPassed.into()),
+        };
+    }
+    let path = CStr::from_ptr(path).to_str()?;
+    NativeStorage::open(path, data, cipher, seal_plaintext).await
+}
+
+/// Exit code of the failed [`create_native_storage`]
+pub fn create_storage_error_code(e: &anyhow::Error) -> ExitCode {
+    match e.downcast_ref::<StorageCipherError>() {
+        Some(StorageCipherError::InvalidKeyLength)
+        | Some(StorageCipherError::FailedToDecrypt)
+        | Some(StorageCipherError::KeyRequired) => ExitCode::BadStorageKey,
+        Some(_) => ExitCode::BadKeystoreData,
+        None if e.is::<hex::FromHexError>() => ExitCode::BadStorageKey,
+        None => ExitCode::BadKeystoreData,
     }
-    let data = cstr_to_string!(data, Err(ExitCode::BadKeystoreData));
-    let ks = ok_or_ret!(
-        open_storage(&data).await,
-        Err(ExitCode::FailedToCreateKeystore)
-    );
-    let ks = ks;
-    Ok(ks)
+}
+
+unsafe fn read_storage_key(key: *const c_char) -> anyhow::Result<Option<StorageCipher>> {
+    if key.is_null() {
+        return Ok(None);
+    }
+    let key = CStr::from_ptr(key).to_str()?;
+    StorageCipher::from_hex(key).map(Some)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn add_key(
-    keystore: *mut KeyStoreWrapper,
-    key_input: *mut c_char,
-    key_name: *mut c_char,
+    keystore: Handle,
+    key_input: *const c_char,
+    key_name: *const c_char,
 ) -> ExitCode {
-    ffi_ensure!(
-        keystore.is_null(),
-        ExitCode::BadKeystoreData,
-        "Keystore is null"
-    );
-    let input = cstr_to_string!(key_input, ExitCode::BadCreateKeyData);
-    let key_name = cstr_to_string!(key_name, ExitCode::BadCreateKeyData);
-    let keystore = ffi_mut_cast(keystore).inner_mut();
-    let key_input: super::models::CreateKeyData =
-        ok_or_ret!(serde_json::from_str(&input), ExitCode::BadCreateKeyData);
-    let res = get_runtime!().block_on(async {
-        match key_input {
-            CreateKeyData::Derived(a) => keystore.add_key::<DerivedKeySigner>(&key_name, a).await,
-            CreateKeyData::Encrypted(a) => {
-                keystore.add_key::<EncryptedKeySigner>(&key_name, a).await
+    catch_panic(|| {
+        let input = cstr_to_string!(key_input, ExitCode::BadCreateKeyData);
+        let key_name = cstr_to_string!(key_name, ExitCode::BadCreateKeyData);
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let key_input: super::models::CreateKeyData =
+            ok_or_ret!(serde_json::from_str(&input), ExitCode::BadCreateKeyData);
+        let res = get_runtime!().block_on(async {
+            let mut inner = keystore.inner().lock().await;
+            let entry = match key_input {
+                CreateKeyData::Derived(a) => {
+                    inner.add_key::<DerivedKeySigner>(&key_name, a).await?
+                }
+                CreateKeyData::Encrypted(a) => {
+                    inner.add_key::<EncryptedKeySigner>(&key_name, a).await?
+                }
+            };
+            keystore.order().push(&entry.public_key).await
+        });
+        ok_or_ret!(res, ExitCode::FailedToAddKey);
+        ExitCode::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn remove_key(keystore: Handle, pubkey: *const c_char) -> ExitCode {
+    catch_panic(|| {
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+
+        let pubkey = ok_or_ret!(read_public_key(pubkey), ExitCode::InvalidPublicKey);
+        let res = get_runtime!().block_on(async {
+            let mut inner = keystore.inner().lock().await;
+            inner.remove_key(&pubkey).await?;
+            keystore.order().remove(&pubkey).await
+        });
+        ok_or_ret!(res, ExitCode::FailedToRemoveKey);
+        ExitCode::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn update_key(keystore: Handle, update_input: *const c_char) -> ExitCode {
+    catch_panic(|| {
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let up_data = cstr_to_string!(update_input, ExitCode::BadUpdateData);
+        let up_data: super::models::UpdateKeyData =
+            ok_or_ret!(serde_json::from_str(&up_data), ExitCode::BadUpdateData);
+        let res = get_runtime!().block_on(async {
+            let mut keystore = keystore.inner().lock().await;
+            match up_data {
+                UpdateKeyData::Derived(a) => keystore.update_key::<DerivedKeySigner>(a).await,
+                UpdateKeyData::Encrypted(a) => keystore.update_key::<EncryptedKeySigner>(a).await,
             }
-        }
-    });
-    ok_or_ret!(res, ExitCode::FailedToAddKey);
-    ExitCode::Ok
+        });
+        ok_or_ret!(res, ExitCode::FailedToUpdateKey);
+        ExitCode::Ok
+    })
 }
 
+/// Exports key, selected by `export_data` json, writing json of its secret into `output`
 #[no_mangle]
-pub unsafe extern "C" fn remove_key(
-    keystore: *mut KeyStoreWrapper,
-    pubkey: *mut c_char,
+pub unsafe extern "C" fn export_key(
+    keystore: Handle,
+    export_data: *const c_char,
+    output: *mut *const c_char,
 ) -> ExitCode {
-    ffi_ensure!(
-        keystore.is_null(),
-        ExitCode::BadKeystoreData,
-        "Keystore is null"
-    );
-    let keystore = ffi_mut_cast(keystore).inner_mut();
+    catch_panic(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Export is null"
+        );
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let export_data = cstr_to_string!(export_data, ExitCode::BadExportData);
+        let export_data: ExportKeyData =
+            ok_or_ret!(serde_json::from_str(&export_data), ExitCode::BadExportData);
+        let export_data = get_runtime!().block_on(async {
+            let keystore = keystore.inner().lock().await;
+            match export_data {
+                ExportKeyData::Derived(a) => keystore
+                    .export_key::<DerivedKeySigner>(a)
+                    .await
+                    .and_then(|x| Ok(serde_json::to_string(&x)?)),
+                ExportKeyData::Encrypted(a) => keystore
+                    .export_key::<EncryptedKeySigner>(a)
+                    .await
+                    .and_then(|x| Ok(serde_json::to_string(&x)?)),
+            }
+        });
+        let export_data = ok_or_ret!(export_data, ExitCode::FailedToExportKey);
+        let export_data = ok_or_ret!(CString::new(export_data), ExitCode::FailedToExportKey);
+        *output = export_data.into_raw();
+        ExitCode::Ok
+    })
+}
 
-    let pubkey = ok_or_ret!(read_public_key(pubkey), ExitCode::InvalidPublicKey);
-    let res = get_runtime!().block_on(async { keystore.remove_key(&pubkey).await });
-    ok_or_ret!(res, ExitCode::FailedToRemoveKey);
-    ExitCode::Ok
+/// Writes json list of the keystore entries in order of creation into `output`
+#[no_mangle]
+pub unsafe extern "C" fn get_entries(keystore: Handle, output: *mut *const c_char) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let entries = get_runtime!().block_on(async {
+            let entries = keystore.inner().lock().await.get_entries().await;
+            let entries = keystore.order().sort(entries).await?;
+            Ok::<_, anyhow::Error>(serde_json::to_string(&entries)?)
+        });
+        let entries = ok_or_ret!(entries, ExitCode::FailedToGetEntries);
+        let entries = ok_or_ret!(CString::new(entries), ExitCode::FailedToGetEntries);
+        *output = entries.into_raw();
+        ExitCode::Ok
+    })
 }
 
+/// Derives accounts of the master key. `input` is json
+/// `{"master_key": .., "password": .., "from": .., "to": ..}`, `to` is inclusive.
+/// Writes json list of the accounts with their addresses for every contract type into `output`
 #[no_mangle]
-pub unsafe extern "C" fn update_key(
-    keystore: *mut KeyStoreWrapper,
-    update_input: *mut c_char,
+pub unsafe extern "C" fn derive_accounts(
+    keystore: Handle,
+    input: *const c_char,
+    output: *mut *const c_char,
 ) -> ExitCode {
-    ffi_ensure!(
-        keystore.is_null(),
-        ExitCode::BadKeystoreData,
-        "Keystore is null"
-    );
-    let keystore = ffi_mut_cast(keystore).inner_mut();
-    let up_data = cstr_to_string!(update_input, ExitCode::BadUpdateData);
-    let up_data: super::models::UpdateKeyData =
-        ok_or_ret!(serde_json::from_str(&up_data), ExitCode::BadUpdateData);
-    let res = get_runtime!().block_on(async {
-        match up_data {
-            UpdateKeyData::Derived(a) => keystore.update_key::<DerivedKeySigner>(a).await,
-            UpdateKeyData::Encrypted(a) => keystore.update_key::<EncryptedKeySigner>(a).await,
-        }
-    });
-    ok_or_ret!(res, ExitCode::FailedToUpdateKey);
-    ExitCode::Ok
+    catch_panic(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let input = cstr_to_string!(input, ExitCode::BadDeriveData);
+        let input: DeriveAccountsInput =
+            ok_or_ret!(serde_json::from_str(&input), ExitCode::BadDeriveData);
+        let accounts = get_runtime!().block_on(async {
+            let keystore = keystore.inner().lock().await;
+            derive::derive_accounts(&keystore, input).await
+        });
+        let accounts = ok_or_ret!(accounts, ExitCode::FailedToDeriveAccounts);
+        let accounts = ok_or_ret!(
+            serde_json::to_string(&accounts),
+            ExitCode::FailedToDeriveAccounts
+        );
+        let accounts = ok_or_ret!(CString::new(accounts), ExitCode::FailedToDeriveAccounts);
+        *output = accounts.into_raw();
+        ExitCode::Ok
+    })
 }
 
+/// Adds accounts of the master key to the keystore. `input` is json
+/// `{"master_key": .., "password": .., "accounts": [{"account_id": .., "name": ..}]}`
 #[no_mangle]
-pub unsafe extern "C" fn export_key(
-    keystore: *mut KeyStoreWrapper,
-    export_data: *mut c_char,
+pub unsafe extern "C" fn add_derived_accounts(keystore: Handle, input: *const c_char) -> ExitCode {
+    catch_panic(|| {
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let input = cstr_to_string!(input, ExitCode::BadDeriveData);
+        let input: AddAccountsInput =
+            ok_or_ret!(serde_json::from_str(&input), ExitCode::BadDeriveData);
+        let res = get_runtime!().block_on(async {
+            let mut inner = keystore.inner().lock().await;
+            derive::add_accounts(&mut inner, keystore.order(), input).await
+        });
+        ok_or_ret!(res, ExitCode::FailedToAddKey);
+        ExitCode::Ok
+    })
+}
+
+/// Signs data with the key, selected by `sign_data` json.
+/// `input` is json `{"data": <base64>, "hash": <sign sha256 of data>}`.
+/// Signed bytes are prefixed with `ntbindings-signed-data:`, so the signature is never valid
+/// for a message or a transaction.
+/// Writes json `{"signature": <base64>, "signature_hex": <hex>}` into `output`
+#[no_mangle]
+pub unsafe extern "C" fn sign_data(
+    keystore: Handle,
+    sign_data: *const c_char,
+    input: *const c_char,
+    output: *mut *const c_char,
+) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let sign_data = cstr_to_string!(sign_data, ExitCode::BadSignData);
+        let sign_data: SignData =
+            ok_or_ret!(serde_json::from_str(&sign_data), ExitCode::BadSignData);
+        let input = cstr_to_string!(input, ExitCode::BadSignData);
+        let input: SignInput = ok_or_ret!(serde_json::from_str(&input), ExitCode::BadSignData);
+        let signed = get_runtime!().block_on(async {
+            let sign_data = keystore.sessions().resolve(sign_data).await?;
+            let inner = keystore.inner().lock().await;
+            signature::sign_data(&inner, &sign_data, input).await
+        });
+        let signed = ok_or_ret!(signed, ExitCode::FailedToSign);
+        let signed = ok_or_ret!(serde_json::to_string(&signed), ExitCode::FailedToSign);
+        let signed = ok_or_ret!(CString::new(signed), ExitCode::FailedToSign);
+        *output = signed.into_raw();
+        ExitCode::Ok
+    })
+}
+
+/// Verifies signature, made by `sign_data`. `input` is json
+/// `{"public_key": <hex>, "data": <base64>, "hash": <sha256 of data was signed>, "signature": <base64 or hex>}`
+#[no_mangle]
+pub unsafe extern "C" fn verify_signature(input: *const c_char, output: *mut bool) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let input = cstr_to_string!(input, ExitCode::BadSignData);
+        let input: VerifyInput = ok_or_ret!(serde_json::from_str(&input), ExitCode::BadSignData);
+        *output = ok_or_ret!(signature::verify_signature(input), ExitCode::BadSignData);
+        ExitCode::Ok
+    })
+}
+
+/// Checks credentials in `sign_data` json without touching the network.
+/// Returns [`ExitCode::BadPassword`] if they are wrong
+#[no_mangle]
+pub unsafe extern "C" fn check_password(keystore: Handle, sign_data: *const c_char) -> ExitCode {
+    catch_panic(|| {
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let sign_data = cstr_to_string!(sign_data, ExitCode::BadSignData);
+        let sign_data: SignData =
+            ok_or_ret!(serde_json::from_str(&sign_data), ExitCode::BadSignData);
+        let res = get_runtime!().block_on(async {
+            let sign_data = keystore.sessions().resolve(sign_data).await?;
+            let inner = keystore.inner().lock().await;
+            signature::check_password(&inner, &sign_data).await
+        });
+        ok_or_ret!(res, ExitCode::BadPassword);
+        ExitCode::Ok
+    })
+}
+
+/// Checks credentials in `sign_data` json and keeps the decrypted key for `seconds`.
+/// Writes id of the session into `session_id`, which can be used as
+/// `{"type": "Session", "id": <session_id>}` sign data instead of the password
+/// with the keystore of the `context`, including its keystore handles.
+/// Every session is forgotten once the context is deleted
+#[no_mangle]
+pub unsafe extern "C" fn unlock_key(
+    context: Handle,
+    sign_data: *const c_char,
+    seconds: c_uint,
+    session_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            session_id.is_null(),
+            ExitCode::NullOutputPointer,
+            "Session id is null"
+        );
+        let context = get_handle!(CONTEXTS, context, ExitCode::NoContextProvided);
+        let sign_data = cstr_to_string!(sign_data, ExitCode::BadSignData);
+        let sign_data: SignData =
+            ok_or_ret!(serde_json::from_str(&sign_data), ExitCode::BadSignData);
+        let duration = Duration::from_secs(seconds as u64);
+        let id = get_runtime!().block_on(async {
+            let sessions = context.keystore.sessions();
+            let sign_data = sessions.resolve(sign_data).await?;
+            let inner = context.keystore.inner().lock().await;
+            let key = signature::unlock(&inner, &sign_data).await?;
+            drop(inner);
+            Ok::<_, anyhow::Error>(sessions.unlock(key, duration).await)
+        });
+        let id = ok_or_ret!(id, ExitCode::BadPassword);
+
+        // Sessions outlive the context, so they are pruned regardless of it
+        let sessions = context.keystore.sessions().clone();
+        OPERATIONS.spawn("prune_sessions", next_request_id(), async move {
+            tokio::time::sleep(duration).await;
+            sessions.prune().await;
+        });
+        *session_id = id;
+        ExitCode::Ok
+    })
+}
+
+/// Forgets key of the unlock session
+#[no_mangle]
+pub unsafe extern "C" fn lock_key(context: Handle, session_id: u64) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, context, ExitCode::NoContextProvided);
+        get_runtime!().block_on(context.keystore.sessions().lock(session_id));
+        ExitCode::Ok
+    })
+}
+
+/// Forgets keys of every unlock session of the keystore of the `context`
+#[no_mangle]
+pub unsafe extern "C" fn lock_all_keys(context: Handle) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, context, ExitCode::NoContextProvided);
+        get_runtime!().block_on(context.keystore.sessions().lock_all());
+        ExitCode::Ok
+    })
+}
+
+/// Writes backup of the whole keystore, encrypted with `password`, into `output`
+#[no_mangle]
+pub unsafe extern "C" fn export_backup(
+    keystore: Handle,
+    password: *const c_char,
     output: *mut *const c_char,
 ) -> ExitCode {
-    ffi_ensure!(
-        keystore.is_null(),
-        ExitCode::BadKeystoreData,
-        "Keystore is null"
-    );
-    ffi_ensure!(
-        output.is_null(),
-        ExitCode::NullOutputPointer,
-        "Export is null"
-    );
-    let keystore = ffi_mut_cast(keystore).inner_mut();
-    let export_data = cstr_to_string!(export_data, ExitCode::BadExportData);
-    let export_data: ExportKeyData =
-        ok_or_ret!(serde_json::from_str(&export_data), ExitCode::BadExportData);
-    let export_data = get_runtime!().block_on(async {
-        match export_data {
-            ExportKeyData::Derived(a) => keystore
-                .export_key::<DerivedKeySigner>(a)
-                .await
-                .map(|x| serde_json::to_string(&x).unwrap()),
-            ExportKeyData::Encrypted(a) => keystore
-                .export_key::<EncryptedKeySigner>(a)
-                .await
-                .map(|x| serde_json::to_string(&x).unwrap()),
-        }
-    });
-    let export_data = ok_or_ret!(export_data, ExitCode::FailedToExportKey);
-    let export_data = CString::new(export_data).unwrap();
-    *output = export_data.into_raw();
-    ExitCode::Ok
-}
-
-#[no_mangle]
-pub unsafe extern "C" fn clear_keystore(keystore: *mut KeyStoreWrapper) -> ExitCode {
-    ffi_ensure!(
-        keystore.is_null(),
-        ExitCode::BadKeystoreData,
-        "Keystore is null"
-    );
-    let keystore = ffi_mut_cast(keystore);
-    let res = get_runtime!().block_on(async { keystore.inner_mut().clear().await });
-    ok_or_ret!(res, ExitCode::FailedToRemoveKey);
-    ExitCode::Ok
+    catch_panic(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let password = cstr_to_string!(password, ExitCode::BadPassword);
+        let exported = ok_or_ret!(
+            get_runtime!().block_on(backup::export_backup(&keystore, &password)),
+            ExitCode::FailedToExportBackup
+        );
+        let exported = ok_or_ret!(CString::new(exported), ExitCode::FailedToExportBackup);
+        *output = exported.into_raw();
+        ExitCode::Ok
+    })
+}
+
+/// Imports backup, made by [`export_backup`], according to `mode`, an [`ImportMode`] value:
+/// `Replace` (0) drops local keys, `DryRun` (1) changes nothing, `Merge` (2) adds backup only
+/// keys, keeping local and conflicting keys as they are.
+/// Writes json report of added, unchanged, conflicting and local only keys into `output`
+#[no_mangle]
+pub unsafe extern "C" fn import_backup(
+    keystore: Handle,
+    backup: *const c_char,
+    password: *const c_char,
+    mode: c_uint,
+    output: *mut *const c_char,
+) -> ExitCode {
+    catch_panic(|| {
+        ffi_ensure!(
+            output.is_null(),
+            ExitCode::NullOutputPointer,
+            "Output is null"
+        );
+        let mode = ok_or_ret!(ImportMode::try_from(mode), ExitCode::BadEnumValue);
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let backup = cstr_to_string!(backup, ExitCode::BadBackup);
+        let password = cstr_to_string!(password, ExitCode::BadPassword);
+        let report = ok_or_ret!(
+            get_runtime!().block_on(backup::import_backup(&keystore, &backup, &password, mode)),
+            ExitCode::BadBackup
+        );
+        let report = ok_or_ret!(serde_json::to_string(&report), ExitCode::BadBackup);
+        let report = ok_or_ret!(CString::new(report), ExitCode::BadBackup);
+        *output = report.into_raw();
+        ExitCode::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn clear_keystore(keystore: Handle) -> ExitCode {
+    catch_panic(|| {
+        let keystore = get_handle!(KEYSTORES, keystore, ExitCode::BadKeystoreData);
+        let res = get_runtime!().block_on(async {
+            keystore.inner().lock().await.clear().await?;
+            keystore.order().clear().await
+        });
+        ok_or_ret!(res, ExitCode::FailedToRemoveKey);
+        ExitCode::Ok
+    })
 }
 
 // pub async fn clear(&self) -> Result<()> {
@@ -1,5 +1,12 @@
+pub(crate) mod backup;
+mod crypto;
+mod derive;
+mod entries;
 pub mod ffi;
 mod models;
+mod schema;
+pub(crate) mod session;
+pub(crate) mod signature;
 
 use nekoton::crypto::{DerivedKeySigner, EncryptedKeySigner};
 
@@ -7,76 +14,586 @@ use anyhow::Error;
 use async_trait::async_trait;
 use nekoton::core::keystore::KeyStore;
 use nekoton::external::Storage;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::RwLock;
 
+use crate::ffi::SendPort;
+pub use crypto::StorageCipher;
+use crypto::StorageCipherError;
+pub use models::KeyStoreWrapper;
+pub use schema::Namespace;
+use schema::{Document, Entries};
+
+/// Storage shared by the keystore and the rest of the bindings.
+///
+/// Entries are split into [`Namespace`]s, clones made by [`NativeStorage::with_namespace`]
+/// share the same data and implement [`Storage`] over their own namespace.
 #[derive(Clone)]
 pub struct NativeStorage {
-    inner: Arc<RwLock<HashMap<String, String>>>,
+    inner: Arc<RwLock<StorageState>>,
+    namespace: Namespace,
+    path: Option<Arc<PathBuf>>,
+    listener: Arc<std::sync::RwLock<Option<SendPort>>>,
+    /// Order of the writes, taken when the write is requested
+    sequence: Arc<AtomicU64>,
+}
+
+struct StorageState {
+    /// Values as they are persisted, i.e. sealed if `cipher` is set
+    document: Document,
+    cipher: Option<StorageCipher>,
+    /// Sequence number of the last write of every key
+    written: HashMap<(Namespace, String), u64>,
+}
+
+impl StorageState {
+    /// Checks that sealed values can be opened if the document header says they are encrypted.
+    /// Plaintext document is rejected if `cipher` is set, unless `seal_plaintext` is set,
+    /// then its values are sealed. Returns whether the document was changed
+    fn load(
+        mut document: Document,
+        cipher: Option<StorageCipher>,
+        seal_plaintext: bool,
+    ) -> Result<(Self, bool), Error> {
+        let mut changed = false;
+        let values = document
+            .namespaces
+            .iter_mut()
+            .flat_map(|(namespace, entries)| {
+                entries
+                    .iter_mut()
+                    .map(move |(key, value)| (*namespace, key, value))
+            });
+        match (document.encryption.clone(), &cipher) {
+            (Some(encryption), Some(cipher)) => {
+                StorageCipher::check(&encryption)?;
+                for (namespace, key, value) in values {
+                    cipher.decrypt(&associated_data(namespace, key), value)?;
+                }
+            }
+            (Some(_), None) => return Err(StorageCipherError::KeyRequired.into()),
+            (None, Some(cipher)) => {
+                for (namespace, key, value) in values {
+                    if !seal_plaintext {
+                        return Err(StorageCipherError::UnexpectedPlaintext.into());
+                    }
+                    *value = cipher.encrypt(&associated_data(namespace, key), value)?;
+                }
+                document.encryption = Some(StorageCipher::encryption());
+                changed = true;
+            }
+            (None, None) => {}
+        }
+        let state = Self {
+            document,
+            cipher,
+            written: HashMap::new(),
+        };
+        Ok((state, changed))
+    }
+
+    fn open(&self, namespace: Namespace, key: &str, value: &str) -> Result<String, Error> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(&associated_data(namespace, key), value),
+            None => Ok(value.to_string()),
+        }
+    }
+
+    fn seal(&self, namespace: Namespace, key: &str, value: &str) -> Result<String, Error> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&associated_data(namespace, key), value),
+            None => Ok(value.to_string()),
+        }
+    }
+}
+
+/// Binds sealed value to its namespace and key, so it can't be moved to another one
+fn associated_data(namespace: Namespace, key: &str) -> String {
+    format!("{}:{}", namespace.as_str(), key)
+}
+
+/// Posted to the listener port on every write or removal
+#[derive(Serialize)]
+struct StorageUpdate<'a> {
+    namespace: Namespace,
+    key: &'a str,
+    /// Whether the key was present before the update
+    old: bool,
+    /// Whether the key is present after the update
+    new: bool,
+}
+
+/// Posted to the listener port, if the write, which nobody awaits, failed
+#[derive(Serialize)]
+struct StorageFailure<'a> {
+    namespace: Namespace,
+    key: &'a str,
+    error: String,
 }
 
 impl NativeStorage {
-    pub fn new(data: &str) -> Result<Self, Error> {
-        let map: HashMap<String, String> = serde_json::from_str(data)?;
+    /// Creates in-memory storage from `data` json of any known schema version.
+    /// If `cipher` is set, values are kept encrypted. Plaintext values of `data` are then sealed
+    /// if `seal_plaintext` is set, otherwise rejected
+    pub fn new(
+        data: &str,
+        cipher: Option<StorageCipher>,
+        seal_plaintext: bool,
+    ) -> Result<Self, Error> {
+        let (document, _) = Document::parse(data.as_bytes())?;
+        let (state, _) = StorageState::load(document, cipher, seal_plaintext)?;
         Ok(Self {
-            inner: Arc::new(RwLock::new(map)),
+            inner: Arc::new(RwLock::new(state)),
+            namespace: Namespace::Keystore,
+            path: None,
+            listener: Default::default(),
+            sequence: Default::default(),
         })
     }
 
+    /// Opens storage backed by the file at `path`, migrating it to the current schema.
+    /// If the file doesn't exist yet, it is created from `initial` (or empty).
+    /// Plaintext values are handled as in [`NativeStorage::new`]
+    pub async fn open<P: AsRef<Path>>(
+        path: P,
+        initial: Option<&str>,
+        cipher: Option<StorageCipher>,
+        seal_plaintext: bool,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        // Leftover of an interrupted write, the main file is still consistent
+        let _ = tokio::fs::remove_file(temp_path(&path)).await;
+
+        let (document, migrated, exists) = match tokio::fs::read(&path).await {
+            Ok(data) => {
+                let (document, migrated) = Document::parse(&data)?;
+                (document, migrated, true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => match initial {
+                Some(data) => {
+                    let (document, _) = Document::parse(data.as_bytes())?;
+                    (document, false, false)
+                }
+                None => (Document::default(), false, false),
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let (state, sealed) = StorageState::load(document, cipher, seal_plaintext)?;
+        if migrated || sealed || !exists {
+            persist(&path, &state.document).await?;
+        }
+
+        Ok(Self {
+            inner: Arc::new(RwLock::new(state)),
+            namespace: Namespace::Keystore,
+            path: Some(Arc::new(path)),
+            listener: Default::default(),
+            sequence: Default::default(),
+        })
+    }
+
+    /// Returns storage sharing the same data, but reading and writing entries of `namespace`
+    pub fn with_namespace(&self, namespace: Namespace) -> Self {
+        Self {
+            namespace,
+            ..self.clone()
+        }
+    }
+
+    /// Plaintext entries of the own namespace
+    pub async fn entries(&self) -> Result<Entries, Error> {
+        let state = self.inner.read().await;
+        let mut entries = Entries::new();
+        if let Some(sealed) = state.document.namespaces.get(&self.namespace) {
+            for (key, value) in sealed.iter() {
+                entries.insert(key.clone(), state.open(self.namespace, key, value)?);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Replaces every entry of the own namespace with plaintext `entries`
+    pub async fn replace_entries(&self, entries: Entries) -> Result<(), Error> {
+        let mut state = self.inner.write().await;
+        let mut sealed = Entries::with_capacity(entries.len());
+        for (key, value) in entries.iter() {
+            sealed.insert(key.clone(), state.seal(self.namespace, key, value)?);
+        }
+        let mut document = state.document.clone();
+        let old = document
+            .namespaces
+            .insert(self.namespace, sealed)
+            .unwrap_or_default();
+        self.commit(&mut state, document).await?;
+        // Pending background writes of these keys are older and must not override them
+        let sequence = self.next_sequence();
+        for key in old.keys().chain(entries.keys()) {
+            state
+                .written
+                .insert((self.namespace, key.clone()), sequence);
+        }
+        drop(state);
+
+        for key in old.keys().filter(|key| !entries.contains_key(*key)) {
+            self.notify(key, true, false);
+        }
+        for key in entries.keys() {
+            self.notify(key, old.contains_key(key), true);
+        }
+        Ok(())
+    }
+
+    /// Json of the versioned document as it is persisted. Values are sealed if the storage is encrypted
     pub async fn dump(&self) -> Result<String, Error> {
-        let data = self.inner.read().await;
-        Ok(serde_json::to_string(&*data)?)
+        let state = self.inner.read().await;
+        Ok(serde_json::to_string(&state.document)?)
+    }
+
+    /// Re-encrypts every value with `cipher`, updating the document header.
+    /// `None` leaves values in plaintext
+    pub async fn rekey(&self, cipher: Option<StorageCipher>) -> Result<(), Error> {
+        let mut state = self.inner.write().await;
+        let mut document = Document {
+            encryption: cipher.as_ref().map(|_| StorageCipher::encryption()),
+            ..Default::default()
+        };
+        for (namespace, entries) in state.document.namespaces.iter() {
+            let mut resealed = Entries::with_capacity(entries.len());
+            for (key, value) in entries.iter() {
+                let value = state.open(*namespace, key, value)?;
+                let value = match &cipher {
+                    Some(cipher) => cipher.encrypt(&associated_data(*namespace, key), &value)?,
+                    None => value,
+                };
+                resealed.insert(key.clone(), value);
+            }
+            document.namespaces.insert(*namespace, resealed);
+        }
+        if let Some(path) = &self.path {
+            persist(path, &document).await?;
+        }
+        state.document = document;
+        state.cipher = cipher;
+        Ok(())
+    }
+
+    /// Persists `document` and only then makes it current, so a failed write changes nothing
+    async fn commit(&self, state: &mut StorageState, document: Document) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            persist(path, &document).await?;
+        }
+        state.document = document;
+        Ok(())
+    }
+
+    /// Sets port, receiving [`StorageUpdate`] json on every change. `None` unsubscribes.
+    pub fn set_listener(&self, port: Option<SendPort>) {
+        if let Ok(mut listener) = self.listener.write() {
+            *listener = port;
+        }
+    }
+
+    fn notify(&self, key: &str, old: bool, new: bool) {
+        self.post(&StorageUpdate {
+            namespace: self.namespace,
+            key,
+            old,
+            new,
+        });
+    }
+
+    fn notify_failure(&self, key: &str, error: &Error) {
+        log::error!("Failed writing {}: {}", key, error);
+        self.post(&StorageFailure {
+            namespace: self.namespace,
+            key,
+            error: error.to_string(),
+        });
+    }
+
+    fn post<T: Serialize>(&self, message: &T) {
+        let port = match self.listener.read() {
+            Ok(listener) => match *listener {
+                Some(port) => port,
+                None => return,
+            },
+            Err(_) => return,
+        };
+        match serde_json::to_string(message) {
+            Ok(message) => {
+                port.post(message);
+            }
+            Err(e) => log::error!("Failed serializing storage update: {}", e),
+        }
+    }
+
+    /// Takes place of the next write in the order of writes
+    fn next_sequence(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Sets `key` to `value` or removes it, if `value` is `None`.
+    /// The write is dropped, if a write of the same key, requested after it, is applied already
+    async fn write(&self, key: &str, value: Option<&str>, sequence: u64) -> Result<(), Error> {
+        let mut state = self.inner.write().await;
+        let written = (self.namespace, key.to_string());
+        if matches!(state.written.get(&written), Some(last) if *last > sequence) {
+            return Ok(());
+        }
+
+        let value = match value {
+            Some(value) => Some(state.seal(self.namespace, key, value)?),
+            None => None,
+        };
+        let mut document = state.document.clone();
+        let entries = document.namespaces.entry(self.namespace).or_default();
+        let (old, new) = match value {
+            Some(value) => (entries.insert(key.to_string(), value).is_some(), true),
+            None => (entries.remove(key).is_some(), false),
+        };
+        if old || new {
+            self.commit(&mut state, document).await?;
+        }
+        state.written.insert(written, sequence);
+        drop(state);
+
+        if old || new {
+            self.notify(key, old, new);
+        }
+        Ok(())
+    }
+
+    /// Applies the write in background, keeping its place in the order of writes.
+    /// Failure is posted to the listener
+    fn write_unchecked(&self, key: &str, value: Option<&str>) {
+        let sequence = self.next_sequence();
+        let (key, value) = (key.to_string(), value.map(str::to_string));
+        let store = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = store.write(&key, value.as_deref(), sequence).await {
+                store.notify_failure(&key, &e);
+            }
+        });
+    }
+}
+
+/// Writes `document` to a temp file next to `path` and atomically renames it over `path`
+async fn persist(path: &Path, document: &Document) -> Result<(), Error> {
+    let data = serde_json::to_vec(document)?;
+    let temp = temp_path(path);
+
+    let mut file = tokio::fs::File::create(&temp).await?;
+    file.write_all(&data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&temp, path).await?;
+    if let Some(dir) = path.parent() {
+        // Makes the rename itself durable. Not supported on every platform
+        if let Ok(dir) = tokio::fs::File::open(dir).await {
+            let _ = dir.sync_all().await;
+        }
     }
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
 }
 
 #[async_trait]
 impl Storage for NativeStorage {
     async fn get(&self, key: &str) -> Result<Option<String>, Error> {
-        Ok(self.inner.read().await.get(key).cloned())
+        let state = self.inner.read().await;
+        let value = state
+            .document
+            .namespaces
+            .get(&self.namespace)
+            .and_then(|entries| entries.get(key));
+        match value {
+            Some(value) => Ok(Some(state.open(self.namespace, key, value)?)),
+            None => Ok(None),
+        }
     }
 
     async fn set(&self, key: &str, value: &str) -> Result<(), Error> {
-        self.inner
-            .write()
-            .await
-            .insert(key.to_string(), value.to_string());
-        Ok(())
+        self.write(key, Some(value), self.next_sequence()).await
     }
 
     fn set_unchecked(&self, key: &str, value: &str) {
-        let (key, value) = (key.to_string(), value.to_string());
-        let store = self.clone();
-        tokio::spawn(async move {
-            let _ = store.set(&key, &value).await;
-        });
+        self.write_unchecked(key, Some(value))
     }
 
     async fn remove(&self, key: &str) -> Result<(), Error> {
-        self.inner.write().await.remove(key);
-        Ok(())
+        self.write(key, None, self.next_sequence()).await
     }
 
     fn remove_unchecked(&self, key: &str) {
-        let key = key.to_string();
-        let store = self.clone();
-        tokio::spawn(async move {
-            let _ = store.remove(&key).await;
-        });
+        self.write_unchecked(key, None)
     }
 }
 
-pub async fn open_storage(data: &str) -> Result<KeyStore, Error> {
-    let storage = NativeStorage::new(data)?;
-    let storage = Arc::new(storage) as Arc<dyn Storage>;
+pub const ENCRYPTED_SIGNER: &str = "encrypted";
+pub const DERIVED_SIGNER: &str = "derived";
+
+pub async fn open_storage(storage: NativeStorage) -> Result<KeyStore, Error> {
+    let storage = Arc::new(storage.with_namespace(Namespace::Keystore)) as Arc<dyn Storage>;
     let der_signer = DerivedKeySigner::new();
 
     let signer = EncryptedKeySigner::new();
     let keystore = KeyStore::builder(storage)
-        .with_signer("encrypted", signer)?
-        .with_signer("derived", der_signer)?
+        .with_signer(ENCRYPTED_SIGNER, signer)?
+        .with_signer(DERIVED_SIGNER, der_signer)?
         .load()
         .await?;
 
     Ok(keystore)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::test_port::{listen, posted};
+
+    fn storage() -> NativeStorage {
+        NativeStorage::new("{}", None, false).unwrap()
+    }
+
+    /// Lets background writes run
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_write_is_dropped() {
+        let storage = storage();
+        storage.write("key", Some("new"), 2).await.unwrap();
+        storage.write("key", Some("old"), 1).await.unwrap();
+        storage.write("key", None, 1).await.unwrap();
+        assert_eq!(storage.get("key").await.unwrap().as_deref(), Some("new"));
+    }
+
+    #[tokio::test]
+    async fn later_write_is_applied() {
+        let storage = storage();
+        storage.set_unchecked("key", "first");
+        storage.set("key", "second").await.unwrap();
+        settle().await;
+        assert_eq!(storage.get("key").await.unwrap().as_deref(), Some("second"));
+
+        storage.set_unchecked("key", "third");
+        storage.remove_unchecked("key");
+        settle().await;
+        assert_eq!(storage.get("key").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn failed_unchecked_write_is_posted() {
+        let dir = std::env::temp_dir().join(format!("ntbindings-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let storage = NativeStorage::open(dir.join("storage.json"), None, None, false)
+            .await
+            .unwrap();
+        storage.set_listener(Some(listen(201)));
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+        storage.set_unchecked("key", "value");
+        for _ in 0..100 {
+            if !posted(201).is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let failure: serde_json::Value = serde_json::from_str(&posted(201)[0]).unwrap();
+        assert_eq!(failure["namespace"], "keystore");
+        assert_eq!(failure["key"], "key");
+        assert!(failure["error"].is_string());
+        assert_eq!(storage.get("key").await.unwrap(), None);
+    }
+
+    fn cipher() -> StorageCipher {
+        StorageCipher::new(&[7; crypto::STORAGE_KEY_LEN]).unwrap()
+    }
+
+    fn cipher_error(error: Error) -> StorageCipherError {
+        error.downcast().unwrap()
+    }
+
+    #[tokio::test]
+    async fn plaintext_value_with_envelope_prefix_stays_plaintext() {
+        let storage = storage().with_namespace(Namespace::AppSettings);
+        storage.set("key", "nt1:not encrypted").await.unwrap();
+
+        let reopened = NativeStorage::new(&storage.dump().await.unwrap(), None, false).unwrap();
+        let reopened = reopened.with_namespace(Namespace::AppSettings);
+        assert_eq!(
+            reopened.get("key").await.unwrap().as_deref(),
+            Some("nt1:not encrypted")
+        );
+    }
+
+    #[tokio::test]
+    async fn encrypted_storage_requires_its_key() {
+        let storage = NativeStorage::new("{}", Some(cipher()), false).unwrap();
+        storage.set("key", "value").await.unwrap();
+        let dump = storage.dump().await.unwrap();
+
+        let error = NativeStorage::new(&dump, None, false).err().unwrap();
+        assert!(matches!(
+            cipher_error(error),
+            StorageCipherError::KeyRequired
+        ));
+        let wrong = StorageCipher::new(&[8; crypto::STORAGE_KEY_LEN]).unwrap();
+        let error = NativeStorage::new(&dump, Some(wrong), false).err().unwrap();
+        assert!(matches!(
+            cipher_error(error),
+            StorageCipherError::FailedToDecrypt
+        ));
+
+        let reopened = NativeStorage::new(&dump, Some(cipher()), false).unwrap();
+        assert_eq!(reopened.get("key").await.unwrap().as_deref(), Some("value"));
+    }
+
+    #[tokio::test]
+    async fn plaintext_storage_is_sealed_only_on_request() {
+        let storage = storage();
+        storage.set("key", "value").await.unwrap();
+        let dump = storage.dump().await.unwrap();
+
+        let error = NativeStorage::new(&dump, Some(cipher()), false)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            cipher_error(error),
+            StorageCipherError::UnexpectedPlaintext
+        ));
+
+        let sealed = NativeStorage::new(&dump, Some(cipher()), true).unwrap();
+        assert_eq!(sealed.get("key").await.unwrap().as_deref(), Some("value"));
+        let dump = sealed.dump().await.unwrap();
+        assert!(NativeStorage::new(&dump, None, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn rekey_updates_header() {
+        let storage = storage();
+        storage.set("key", "value").await.unwrap();
+        storage.rekey(Some(cipher())).await.unwrap();
+        let dump = storage.dump().await.unwrap();
+        assert!(NativeStorage::new(&dump, None, false).is_err());
+
+        storage.rekey(None).await.unwrap();
+        let dump = storage.dump().await.unwrap();
+        let reopened = NativeStorage::new(&dump, None, false).unwrap();
+        assert_eq!(reopened.get("key").await.unwrap().as_deref(), Some("value"));
+    }
+}
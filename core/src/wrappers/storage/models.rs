@@ -1,9 +1,17 @@
+use std::sync::Arc;
+
+use anyhow::Error;
 use nekoton::core::keystore::KeyStore;
 use nekoton::crypto::{
     DerivedKeyCreateInput, DerivedKeyExportParams, DerivedKeyUpdateParams, EncryptedKeyCreateInput,
     EncryptedKeyPassword, EncryptedKeyUpdateParams,
 };
 use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::entries::KeyOrder;
+use super::session::UnlockSessions;
+use super::{open_storage, Namespace, NativeStorage};
 
 #[derive(Serialize, Deserialize)]
 pub enum CreateKeyData {
@@ -23,14 +31,42 @@ pub enum ExportKeyData {
     Encrypted(EncryptedKeyPassword),
 }
 
-pub struct KeyStoreWrapper(KeyStore);
+/// Keystore handle, shared between [`crate::context::Context`] and the keystore FFI
+#[derive(Clone)]
+pub struct KeyStoreWrapper {
+    keystore: Arc<Mutex<KeyStore>>,
+    storage: NativeStorage,
+    order: KeyOrder,
+    sessions: UnlockSessions,
+}
 
 impl KeyStoreWrapper {
-    pub fn inner(&self) -> &KeyStore {
-        &self.0
+    pub async fn open(storage: NativeStorage) -> Result<Self, Error> {
+        let storage = storage.with_namespace(Namespace::Keystore);
+        let keystore = open_storage(storage.clone()).await?;
+        Ok(Self {
+            keystore: Arc::new(Mutex::new(keystore)),
+            order: KeyOrder::new(storage.clone()),
+            sessions: UnlockSessions::default(),
+            storage,
+        })
+    }
+
+    /// Keystore namespace of the underlying storage
+    pub fn storage(&self) -> &NativeStorage {
+        &self.storage
+    }
+
+    pub fn inner(&self) -> &Arc<Mutex<KeyStore>> {
+        &self.keystore
+    }
+
+    pub fn order(&self) -> &KeyOrder {
+        &self.order
     }
 
-    pub fn inner_mut(&mut self) -> &mut KeyStore {
-        &mut self.0
+    /// Unlocked keys of the keystore
+    pub fn sessions(&self) -> &UnlockSessions {
+        &self.sessions
     }
 }
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::NekotonError;
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Version of the persisted storage document
+pub const CURRENT_VERSION: u32 = 1;
+
+type Migration = fn(Value) -> Result<Value, Error>;
+
+/// `MIGRATIONS[n]` upgrades document of version `n` to version `n + 1`
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [migrate_v0_to_v1];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(C)]
+pub enum Namespace {
+    /// Entries of the nekoton keystore
+    Keystore = 0,
+    /// Cached wallet state
+    WalletCache = 1,
+    /// Settings of the app
+    AppSettings = 2,
+}
+
+/// Namespace, passed over FFI as its value
+impl TryFrom<u32> for Namespace {
+    type Error = NekotonError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Namespace::Keystore),
+            1 => Ok(Namespace::WalletCache),
+            2 => Ok(Namespace::AppSettings),
+            _ => Err(NekotonError::UnknownEnumValue("namespace", value)),
+        }
+    }
+}
+
+impl Namespace {
+    /// Name of the namespace in the persisted document
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Namespace::Keystore => "keystore",
+            Namespace::WalletCache => "wallet_cache",
+            Namespace::AppSettings => "app_settings",
+        }
+    }
+}
+
+pub type Entries = HashMap<String, String>;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Document {
+    pub version: u32,
+    /// Encryption of every value of the document, `None` if values are plaintext
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    #[serde(default)]
+    pub namespaces: HashMap<Namespace, Entries>,
+}
+
+/// Header of the encrypted document
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Encryption {
+    /// Version of the sealed value format
+    pub version: u32,
+    /// Derivation of the value key from the host supplied key
+    pub kdf: String,
+    pub aead: String,
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            encryption: None,
+            namespaces: HashMap::new(),
+        }
+    }
+}
+
+impl Document {
+    /// Parses document of any known version, upgrading it to [`CURRENT_VERSION`].
+    /// Returns whether any migration was applied
+    pub fn parse(data: &[u8]) -> Result<(Self, bool), Error> {
+        let mut value: Value = serde_json::from_slice(data)?;
+
+        let version = version_of(&value)?;
+        if version > CURRENT_VERSION {
+            return Err(SchemaError::UnsupportedVersion(version as u64).into());
+        }
+        for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            log::info!("Migrating storage from version {}", from);
+            value = migration(value)?;
+        }
+
+        Ok((serde_json::from_value(value)?, version != CURRENT_VERSION))
+    }
+}
+
+fn version_of(value: &Value) -> Result<u32, Error> {
+    let object = match value {
+        Value::Object(object) => object,
+        _ => return Err(SchemaError::InvalidDocument.into()),
+    };
+    // Version 0 is a flat map without any header
+    if !object.contains_key("namespaces") {
+        return Ok(0);
+    }
+    match object.get("version").and_then(Value::as_u64) {
+        Some(version) => {
+            u32::try_from(version).map_err(|_| SchemaError::UnsupportedVersion(version).into())
+        }
+        None => Err(SchemaError::InvalidDocument.into()),
+    }
+}
+
+/// Flat map, written by the keystore only, becomes the keystore namespace
+fn migrate_v0_to_v1(value: Value) -> Result<Value, Error> {
+    let entries: Entries = serde_json::from_value(value)?;
+    Ok(json!({
+        "version": 1,
+        "namespaces": { "keystore": entries },
+    }))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SchemaError {
+    #[error("Storage document is malformed")]
+    InvalidDocument,
+    #[error("Storage version {0} is newer than supported")]
+    UnsupportedVersion(u64),
+    #[error("Namespace is written by the keystore only")]
+    ReservedNamespace,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespace_is_converted_from_its_value() {
+        for namespace in [
+            Namespace::Keystore,
+            Namespace::WalletCache,
+            Namespace::AppSettings,
+        ]
+        .iter()
+        .copied()
+        {
+            assert_eq!(Namespace::try_from(namespace as u32).unwrap(), namespace);
+        }
+        assert!(Namespace::try_from(3).is_err());
+    }
+
+    #[test]
+    fn flat_map_is_migrated_to_keystore_namespace() {
+        let (document, migrated) = Document::parse(br#"{"key": "value"}"#).unwrap();
+        assert!(migrated);
+        assert_eq!(document.version, CURRENT_VERSION);
+        assert_eq!(
+            document.namespaces[&Namespace::Keystore].get("key"),
+            Some(&"value".to_string())
+        );
+    }
+
+    #[test]
+    fn empty_flat_map_is_migrated() {
+        let (document, migrated) = Document::parse(b"{}").unwrap();
+        assert!(migrated);
+        assert!(document.namespaces[&Namespace::Keystore].is_empty());
+    }
+
+    #[test]
+    fn current_version_is_not_migrated() {
+        let data = br#"{"version": 1, "namespaces": {"app_settings": {"theme": "dark"}}}"#;
+        let (document, migrated) = Document::parse(data).unwrap();
+        assert!(!migrated);
+        assert_eq!(
+            document.namespaces[&Namespace::AppSettings].get("theme"),
+            Some(&"dark".to_string())
+        );
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let data = br#"{"version": 2, "namespaces": {}}"#;
+        assert!(Document::parse(data).is_err());
+    }
+
+    #[test]
+    fn version_out_of_u32_range_is_rejected() {
+        // Would be truncated to 1 by `as u32`
+        let data = br#"{"version": 4294967297, "namespaces": {}}"#;
+        let error = Document::parse(data).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<SchemaError>(),
+            Some(SchemaError::UnsupportedVersion(4294967297))
+        ));
+    }
+
+    #[test]
+    fn malformed_documents_are_rejected() {
+        assert!(Document::parse(b"[]").is_err());
+        assert!(Document::parse(br#"{"namespaces": {}}"#).is_err());
+        assert!(Document::parse(br#"{"key": 1}"#).is_err());
+    }
+
+    #[test]
+    fn serialized_document_parses_back() {
+        let mut document = Document::default();
+        document
+            .namespaces
+            .entry(Namespace::WalletCache)
+            .or_default()
+            .insert("state".to_string(), "{}".to_string());
+        let data = serde_json::to_vec(&document).unwrap();
+        let (parsed, migrated) = Document::parse(&data).unwrap();
+        assert!(!migrated);
+        assert_eq!(parsed.namespaces, document.namespaces);
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use ed25519_dalek::{Keypair, PublicKey, Signer};
+use tokio::sync::Mutex;
+
+use crate::wrappers::SignData;
+
+/// Keys, unlocked for a limited time. Shared by every clone of the keystore handle
+#[derive(Clone, Default)]
+pub struct UnlockSessions {
+    sessions: Arc<Mutex<HashMap<u64, Session>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+struct Session {
+    key: Arc<UnlockedKey>,
+    expires_at: Instant,
+}
+
+/// Decrypted key pair of the keystore key.
+/// Secret key is zeroized by `ed25519_dalek` once the last reference is dropped
+pub struct UnlockedKey(Keypair);
+
+impl UnlockedKey {
+    pub fn new(keypair: Keypair) -> Self {
+        Self(keypair)
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.0.public
+    }
+
+    pub fn sign(&self, data: &[u8]) -> [u8; 64] {
+        self.0.sign(data).to_bytes()
+    }
+}
+
+impl UnlockSessions {
+    /// Keeps `key` for `duration`, returning id of the session
+    pub async fn unlock(&self, key: Arc<UnlockedKey>, duration: Duration) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Session {
+            key,
+            expires_at: Instant::now() + duration,
+        };
+        self.sessions.lock().await.insert(id, session);
+        id
+    }
+
+    pub async fn lock(&self, id: u64) {
+        self.sessions.lock().await.remove(&id);
+    }
+
+    pub async fn lock_all(&self) {
+        self.sessions.lock().await.clear();
+    }
+
+    /// Drops sessions, which are expired
+    pub async fn prune(&self) {
+        let now = Instant::now();
+        self.sessions
+            .lock()
+            .await
+            .retain(|_, session| session.expires_at > now);
+    }
+
+    /// Replaces [`SignData::Session`] with the key, unlocked by the session
+    pub async fn resolve(&self, sign_data: SignData) -> Result<SignData, Error> {
+        let id = match sign_data {
+            SignData::Session { id } => id,
+            sign_data => return Ok(sign_data),
+        };
+        let mut sessions = self.sessions.lock().await;
+        match sessions.get(&id) {
+            Some(session) if session.expires_at > Instant::now() => {
+                Ok(SignData::Unlocked(session.key.clone()))
+            }
+            Some(_) => {
+                sessions.remove(&id);
+                Err(SessionError::Expired.into())
+            }
+            None => Err(SessionError::NotFound.into()),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SessionError {
+    #[error("Unlock session not found")]
+    NotFound,
+    #[error("Unlock session expired")]
+    Expired,
+    #[error("Unlock session must be resolved before signing")]
+    Unresolved,
+    #[error("Key to unlock is not found")]
+    KeyNotFound,
+    #[error("Unlocked key doesn't match the requested public key")]
+    KeyMismatch,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Arc<UnlockedKey> {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[1; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Arc::new(UnlockedKey::new(Keypair { secret, public }))
+    }
+
+    #[tokio::test]
+    async fn resolves_unlocked_key() {
+        let sessions = UnlockSessions::default();
+        let key = key();
+        let public_key = *key.public_key();
+        let id = sessions.unlock(key, Duration::from_secs(60)).await;
+
+        match sessions.resolve(SignData::Session { id }).await.unwrap() {
+            SignData::Unlocked(key) => assert_eq!(*key.public_key(), public_key),
+            _ => panic!("session is not resolved"),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_and_locked_sessions() {
+        let sessions = UnlockSessions::default();
+        let expired = sessions.unlock(key(), Duration::from_secs(0)).await;
+        let locked = sessions.unlock(key(), Duration::from_secs(60)).await;
+        sessions.lock(locked).await;
+
+        for id in [expired, locked, 100].iter().copied() {
+            assert!(sessions.resolve(SignData::Session { id }).await.is_err());
+        }
+        assert!(sessions.sessions.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn prune_drops_expired_keys() {
+        let sessions = UnlockSessions::default();
+        sessions.unlock(key(), Duration::from_secs(0)).await;
+        let alive = sessions.unlock(key(), Duration::from_secs(60)).await;
+        sessions.prune().await;
+
+        let sessions = sessions.sessions.lock().await;
+        assert_eq!(sessions.keys().copied().collect::<Vec<_>>(), vec![alive]);
+    }
+}
@@ -0,0 +1,182 @@
+use std::sync::Arc;
+
+use anyhow::Error;
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use nekoton::core::keystore::KeyStore;
+use nekoton::crypto::{
+    self, DerivedKeyExportParams, DerivedKeySignParams, DerivedKeySigner, EncryptedKeySigner,
+    MnemonicType,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::session::{SessionError, UnlockedKey};
+use super::DERIVED_SIGNER;
+use crate::wrappers::SignData;
+
+/// Domain of the signatures made by [`sign_data`]
+const SIGNED_DATA_PREFIX: &[u8] = b"ntbindings-signed-data:";
+
+#[derive(Deserialize)]
+pub struct SignInput {
+    /// Base64 encoded data
+    data: String,
+    /// Sign sha256 of `data` instead of `data` itself
+    #[serde(default)]
+    hash: bool,
+}
+
+#[derive(Serialize)]
+pub struct SignOutput {
+    /// Base64 encoded signature
+    signature: String,
+    /// Hex encoded signature
+    signature_hex: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyInput {
+    /// Hex encoded public key
+    public_key: String,
+    /// Base64 encoded data
+    data: String,
+    /// Whether sha256 of `data` was signed
+    #[serde(default)]
+    hash: bool,
+    /// Base64 or hex encoded signature
+    signature: String,
+}
+
+/// Signs `data` with the key, selected by `sign_data`
+pub async fn sign(
+    keystore: &KeyStore,
+    sign_data: &SignData,
+    data: &[u8],
+) -> Result<[u8; 64], Error> {
+    match sign_data {
+        SignData::Derived(a) => keystore.sign::<DerivedKeySigner>(data, a.clone()).await,
+        SignData::Encrypted(a) => keystore.sign::<EncryptedKeySigner>(data, a.clone()).await,
+        SignData::Unlocked(key) => Ok(key.sign(data)),
+        SignData::Session { .. } => Err(SessionError::Unresolved.into()),
+    }
+}
+
+/// Decrypts the key, selected by `sign_data`, for an unlock session
+pub async fn unlock(keystore: &KeyStore, sign_data: &SignData) -> Result<Arc<UnlockedKey>, Error> {
+    let (keypair, expected) = match sign_data {
+        SignData::Derived(params) => {
+            let (master_key, account_id, password, expected) = match params.clone() {
+                DerivedKeySignParams::ByAccountId {
+                    master_key,
+                    account_id,
+                    password,
+                } => (master_key, account_id, password, None),
+                DerivedKeySignParams::ByPublicKey {
+                    master_key,
+                    public_key,
+                    password,
+                } => {
+                    let entry = keystore
+                        .get_entries()
+                        .await
+                        .into_iter()
+                        .find(|entry| entry.public_key == public_key)
+                        .ok_or(SessionError::KeyNotFound)?;
+                    (master_key, entry.account_id, password, Some(public_key))
+                }
+            };
+            let exported = keystore
+                .export_key::<DerivedKeySigner>(DerivedKeyExportParams {
+                    master_key,
+                    password,
+                })
+                .await?;
+            let phrase = exported.phrase.unsecure();
+            let keypair = crypto::derive_from_phrase(phrase, MnemonicType::Labs(account_id))?;
+            (keypair, expected)
+        }
+        SignData::Encrypted(params) => {
+            let exported = keystore
+                .export_key::<EncryptedKeySigner>(params.clone())
+                .await?;
+            let keypair =
+                crypto::derive_from_phrase(exported.phrase.unsecure(), exported.mnemonic_type)?;
+            (keypair, Some(params.public_key))
+        }
+        SignData::Unlocked(key) => return Ok(key.clone()),
+        SignData::Session { .. } => return Err(SessionError::Unresolved.into()),
+    };
+    match expected {
+        Some(public_key) if public_key != keypair.public => Err(SessionError::KeyMismatch.into()),
+        _ => Ok(Arc::new(UnlockedKey::new(keypair))),
+    }
+}
+
+/// Public key of the key, selected by `sign_data`, without decrypting it
+pub async fn public_key(keystore: &KeyStore, sign_data: &SignData) -> Result<PublicKey, Error> {
+    match sign_data {
+        SignData::Derived(DerivedKeySignParams::ByAccountId {
+            master_key,
+            account_id,
+            ..
+        }) => keystore
+            .get_entries()
+            .await
+            .into_iter()
+            .find(|entry| {
+                entry.signer_name == DERIVED_SIGNER
+                    && entry.master_key == *master_key
+                    && entry.account_id == *account_id
+            })
+            .map(|entry| entry.public_key)
+            .ok_or_else(|| SessionError::KeyNotFound.into()),
+        SignData::Derived(DerivedKeySignParams::ByPublicKey { public_key, .. }) => Ok(*public_key),
+        SignData::Encrypted(params) => Ok(params.public_key),
+        SignData::Unlocked(key) => Ok(*key.public_key()),
+        SignData::Session { .. } => Err(SessionError::Unresolved.into()),
+    }
+}
+
+/// Checks credentials by signing dummy data, without touching the network
+pub async fn check_password(keystore: &KeyStore, sign_data: &SignData) -> Result<(), Error> {
+    sign(keystore, sign_data, &[0; 32]).await.map(|_| ())
+}
+
+pub async fn sign_data(
+    keystore: &KeyStore,
+    sign_data: &SignData,
+    input: SignInput,
+) -> Result<SignOutput, Error> {
+    let data = prepare_data(&input.data, input.hash)?;
+    let signature = sign(keystore, sign_data, &data).await?;
+    Ok(SignOutput {
+        signature: base64::encode(&signature),
+        signature_hex: hex::encode(&signature),
+    })
+}
+
+pub fn verify_signature(input: VerifyInput) -> Result<bool, Error> {
+    let public_key = PublicKey::from_bytes(&hex::decode(&input.public_key)?)?;
+    let data = prepare_data(&input.data, input.hash)?;
+    let signature = match hex::decode(&input.signature) {
+        Ok(signature) => signature,
+        Err(_) => base64::decode(&input.signature)?,
+    };
+    let signature = Signature::from_bytes(&signature)?;
+    Ok(public_key.verify(&data, &signature).is_ok())
+}
+
+/// Bytes, which are actually signed: the domain prefix followed by `data` or its sha256.
+///
+/// Messages and transactions are signed as bare 32 byte hashes. Signed data is always longer,
+/// so its signature can't be reused for them, whatever the caller passes
+fn prepare_data(data: &str, hash: bool) -> Result<Vec<u8>, Error> {
+    let data = base64::decode(data)?;
+    let mut prepared = SIGNED_DATA_PREFIX.to_vec();
+    if hash {
+        prepared.extend_from_slice(&Sha256::digest(&data));
+    } else {
+        prepared.extend_from_slice(&data);
+    }
+    Ok(prepared)
+}
@@ -0,0 +1,369 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use nekoton::core::keystore::KeyStore;
+use nekoton::core::ton_wallet::{wallet_v3, ContractType, TonWallet, TransferAction};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use ton_block::{AccountStuff, MsgAddressInt};
+
+use super::deploy::{deploy, Deployment};
+use super::fees;
+use super::multisig::check_signer;
+use super::retry::{RetryPolicy, SendAttempt};
+use super::{
+    fetch_state, parse_expiration, parse_payload, prepare_transfer, send_with_retries, SendError,
+    SentMessage, SignData, Transfer, TransferParamsError,
+};
+use crate::ffi::ReplyErrorCode;
+use crate::{GqlTransport, TonWalletSubscription};
+
+/// Max number of outgoing messages, carried by the single external message of WalletV3
+const WALLET_V3_MAX_MESSAGES: usize = 4;
+
+/// Max number of recipients of the single batch
+const MAX_RECIPIENTS: usize = 100;
+
+/// Send mode of the outgoing messages: pay fees separately and ignore errors
+const GIFT_FLAGS: u8 = 3;
+
+#[derive(Deserialize)]
+pub struct BatchParams {
+    recipients: Vec<RecipientParams>,
+    /// Lifetime of every external message in seconds, one minute by default
+    timeout_secs: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct RecipientParams {
+    to: String,
+    amount: u64,
+    comment: Option<String>,
+    /// Base64 encoded BOC of the message body, can't be combined with `comment`
+    payload: Option<String>,
+    #[serde(default)]
+    bounce: bool,
+}
+
+impl BatchParams {
+    pub fn parse(self) -> Result<Vec<Transfer>, TransferParamsError> {
+        if self.recipients.is_empty() {
+            return Err(TransferParamsError::NoRecipients);
+        }
+        if self.recipients.len() > MAX_RECIPIENTS {
+            return Err(TransferParamsError::TooManyRecipients);
+        }
+        let expiration = parse_expiration(self.timeout_secs)?;
+        self.recipients
+            .into_iter()
+            .enumerate()
+            .map(|(index, recipient)| {
+                Ok(Transfer {
+                    to: MsgAddressInt::from_str(&recipient.to)
+                        .map_err(|_| TransferParamsError::InvalidAddress(index))?,
+                    amount: recipient.amount,
+                    payload: parse_payload(recipient.payload, &recipient.comment)?,
+                    comment: recipient.comment,
+                    bounce: recipient.bounce,
+                    expiration,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Progress of `send_batch`, posted before the final reply
+#[derive(Serialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum BatchProgress {
+    Attempt {
+        /// Index of the external message
+        message: usize,
+        #[serde(flatten)]
+        attempt: SendAttempt,
+    },
+    /// External message was included into a transaction
+    Sent {
+        message: usize,
+        /// Indices of the recipients, paid by the message
+        recipients: Vec<usize>,
+        /// Hex encoded hash of the message
+        message_hash: String,
+    },
+}
+
+/// Failure of `send_batch`. Messages, sent before it, are replied as `payload` of the error,
+/// so that the recipients they paid aren't paid again
+pub struct BatchError {
+    pub sent: Vec<SentMessage>,
+    pub error: SendError,
+}
+
+impl From<SendError> for BatchError {
+    fn from(error: SendError) -> Self {
+        Self {
+            sent: Vec::new(),
+            error,
+        }
+    }
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl ReplyErrorCode for BatchError {
+    fn code(&self) -> &'static str {
+        self.error.code()
+    }
+
+    fn payload(&self) -> Option<serde_json::Value> {
+        if self.sent.is_empty() {
+            return None;
+        }
+        serde_json::to_value(&self.sent).ok()
+    }
+}
+
+/// Max number of recipients, paid by the single external message of the wallet
+fn max_messages(contract_type: ContractType) -> usize {
+    match contract_type {
+        ContractType::WalletV3 => WALLET_V3_MAX_MESSAGES,
+        _ => 1,
+    }
+}
+
+/// Pays the recipients with as few external messages as the wallet allows,
+/// sending the next message only after the previous one is confirmed.
+/// Sending stops at the first failed message
+pub async fn send_batch<R>(
+    keystore: Arc<Mutex<KeyStore>>,
+    keystore_type: SignData,
+    transfers: Vec<Transfer>,
+    ton_wallet: Arc<TonWalletSubscription>,
+    transport: Arc<GqlTransport>,
+    policy: RetryPolicy,
+    report: R,
+) -> Result<Vec<SentMessage>, BatchError>
+where
+    R: Fn(BatchProgress) + Sync,
+{
+    check_signer(&keystore, &keystore_type, ton_wallet.inner.public_key()).await?;
+    let confirmations = ton_wallet.confirmations.clone();
+    let mut ton_wallet = ton_wallet.inner.clone();
+    let transport = transport.inner.clone();
+
+    let chunk_size = max_messages(ton_wallet.contract_type());
+    let mut sent = Vec::new();
+    for (index, chunk) in transfers.chunks(chunk_size).enumerate() {
+        let report_attempt = |attempt: SendAttempt| {
+            report(BatchProgress::Attempt {
+                message: index,
+                attempt,
+            })
+        };
+
+        let message: Result<_, SendError> = async {
+            fees::check_funds(&ton_wallet, transport.as_ref(), chunk).await?;
+            let state = fetch_state(transport.as_ref(), ton_wallet.address()).await?;
+            let mut message = match prepare_chunk(&mut ton_wallet, &state, chunk)? {
+                TransferAction::Sign(message) => message,
+                TransferAction::DeployFirst => {
+                    deploy(
+                        &keystore,
+                        &keystore_type,
+                        &mut ton_wallet,
+                        &confirmations,
+                        &Deployment::single(chunk[0].expiration),
+                        &policy,
+                        &report_attempt,
+                    )
+                    .await?;
+                    let state = fetch_state(transport.as_ref(), ton_wallet.address()).await?;
+                    match prepare_chunk(&mut ton_wallet, &state, chunk)? {
+                        TransferAction::Sign(message) => message,
+                        TransferAction::DeployFirst => return Err(SendError::DeployError),
+                    }
+                }
+            };
+
+            send_with_retries(
+                &keystore,
+                &keystore_type,
+                &mut ton_wallet,
+                &confirmations,
+                &mut message,
+                &policy,
+                &report_attempt,
+            )
+            .await
+        }
+        .await;
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => return Err(BatchError { sent, error }),
+        };
+        let first = index * chunk_size;
+        report(BatchProgress::Sent {
+            message: index,
+            recipients: (first..first + chunk.len()).collect(),
+            message_hash: message.message_hash.clone(),
+        });
+        sent.push(message);
+    }
+    Ok(sent)
+}
+
+/// Builds the single external message, paying every recipient of the `chunk`
+pub fn prepare_chunk(
+    ton_wallet: &mut TonWallet,
+    state: &AccountStuff,
+    chunk: &[Transfer],
+) -> Result<TransferAction, SendError> {
+    if let [transfer] = chunk {
+        return prepare_transfer(ton_wallet, state, transfer);
+    }
+
+    let gifts = chunk
+        .iter()
+        .map(|transfer| wallet_v3::Gift {
+            flags: GIFT_FLAGS,
+            bounce: transfer.bounce,
+            destination: transfer.to.clone(),
+            amount: transfer.amount,
+            body: transfer.body(),
+            state_init: None,
+        })
+        .collect();
+    // Transfers of the batch share the expiration
+    let expiration = chunk[0].expiration;
+    Ok(wallet_v3::prepare_transfer(
+        ton_wallet.public_key(),
+        state,
+        gifts,
+        expiration,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use ton_types::BuilderData;
+
+    use super::*;
+    use crate::ffi::AsyncReply;
+
+    const ADDRESS: &str = "0:0000000000000000000000000000000000000000000000000000000000000000";
+
+    fn parse(recipients: serde_json::Value) -> Result<Vec<Transfer>, TransferParamsError> {
+        serde_json::from_value::<BatchParams>(json!({ "recipients": recipients }))
+            .unwrap()
+            .parse()
+    }
+
+    fn recipients(count: usize) -> serde_json::Value {
+        (0..count)
+            .map(|_| json!({ "to": ADDRESS, "amount": 1 }))
+            .collect()
+    }
+
+    fn sent(message_hash: &str) -> SentMessage {
+        SentMessage {
+            message_hash: message_hash.to_string(),
+            expire_at: 1,
+            transaction_id: None,
+            transaction: None,
+        }
+    }
+
+    #[test]
+    fn wallet_v3_pays_up_to_four_recipients_per_message() {
+        let transfers = parse(recipients(9)).unwrap();
+        let chunks: Vec<usize> = transfers
+            .chunks(max_messages(ContractType::WalletV3))
+            .map(|chunk| chunk.len())
+            .collect();
+        assert_eq!(chunks, vec![4, 4, 1]);
+    }
+
+    #[test]
+    fn other_wallets_pay_one_recipient_per_message() {
+        for &contract_type in crate::ContractType::ALL.iter() {
+            let contract_type = ContractType::from(contract_type);
+            if contract_type != ContractType::WalletV3 {
+                assert_eq!(max_messages(contract_type), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_payload_and_comment_of_every_recipient() {
+        let cell = BuilderData::new().into_cell().unwrap();
+        let payload = base64::encode(ton_types::serialize_toc(&cell).unwrap());
+        let transfers = parse(json!([
+            { "to": ADDRESS, "amount": 1, "comment": "first" },
+            { "to": ADDRESS, "amount": 2, "payload": payload, "bounce": true },
+            { "to": ADDRESS, "amount": 3 },
+        ]))
+        .unwrap();
+
+        assert_eq!(transfers[0].comment.as_deref(), Some("first"));
+        assert!(transfers[0].payload.is_none() && transfers[0].body().is_some());
+        assert_eq!(transfers[1].payload, Some(cell));
+        assert!(transfers[1].bounce);
+        assert!(transfers[2].body().is_none());
+        assert_eq!(
+            transfers.iter().map(|t| t.amount).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_recipients() {
+        let error = parse(json!([
+            { "to": ADDRESS, "amount": 1 },
+            { "to": "invalid", "amount": 1 },
+        ]))
+        .err()
+        .unwrap();
+        assert!(matches!(error, TransferParamsError::InvalidAddress(1)));
+
+        let error = parse(json!([
+            { "to": ADDRESS, "amount": 1, "comment": "comment", "payload": "te6ccgEBAQEAAgAAAA==" },
+        ]))
+        .err()
+        .unwrap();
+        assert!(matches!(error, TransferParamsError::CommentWithPayload));
+
+        let error = parse(recipients(MAX_RECIPIENTS + 1)).err().unwrap();
+        assert!(matches!(error, TransferParamsError::TooManyRecipients));
+        let error = parse(json!([])).err().unwrap();
+        assert!(matches!(error, TransferParamsError::NoRecipients));
+    }
+
+    #[test]
+    fn failure_replies_sent_messages() {
+        let error = BatchError {
+            sent: vec![sent("aa"), sent("bb")],
+            error: SendError::Expired,
+        };
+        let reply = AsyncReply::<()>::new(1, Ok(Err(error)));
+        let reply = serde_json::to_value(&reply).unwrap();
+        assert_eq!(reply["status"], "error");
+        assert_eq!(reply["code"], "Expired");
+        assert_eq!(reply["payload"][0]["message_hash"], "aa");
+        assert_eq!(reply["payload"][1]["message_hash"], "bb");
+    }
+
+    #[test]
+    fn failure_before_sending_has_no_payload() {
+        let error = BatchError::from(SendError::InvalidPassword);
+        let reply = AsyncReply::<()>::new(1, Ok(Err(error)));
+        let reply = serde_json::to_value(&reply).unwrap();
+        assert_eq!(reply["code"], "InvalidPassword");
+        assert!(reply.get("payload").is_none());
+    }
+}
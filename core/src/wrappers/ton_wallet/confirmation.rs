@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nekoton::core::models::{PendingTransaction, Transaction};
+use ton_types::UInt256;
+
+/// Outcomes of the outgoing messages are kept this long after they expire, if nobody took them
+const KEEP_OUTCOME_SECS: u32 = 600;
+
+/// How the sent message ended up
+pub enum Outcome {
+    /// Message was included into the transaction
+    Sent(Option<Transaction>),
+    /// Message wasn't included until it expired
+    Expired,
+}
+
+/// Outcomes of the outgoing messages by message hash.
+///
+/// Filled by the subscription handler, so it doesn't matter which clone of the wallet
+/// noticed the transaction first
+#[derive(Clone, Default)]
+pub struct Confirmations {
+    outcomes: Arc<Mutex<HashMap<UInt256, (u32, Outcome)>>>,
+}
+
+impl Confirmations {
+    pub fn resolve(&self, pending: &PendingTransaction, outcome: Outcome) {
+        self.insert(pending.message_hash.clone(), pending.expire_at, outcome);
+    }
+
+    fn insert(&self, message_hash: UInt256, expire_at: u32, outcome: Outcome) {
+        let now = now();
+        let mut outcomes = self.lock();
+        outcomes.retain(|_, (expire_at, _)| expire_at.saturating_add(KEEP_OUTCOME_SECS) > now);
+        outcomes.insert(message_hash, (expire_at, outcome));
+    }
+
+    /// Takes outcome of the message, if it's known already
+    pub fn take(&self, message_hash: &UInt256) -> Option<Outcome> {
+        self.lock().remove(message_hash).map(|(_, outcome)| outcome)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<UInt256, (u32, Outcome)>> {
+        match self.outcomes.lock() {
+            Ok(outcomes) => outcomes,
+            Err(e) => e.into_inner(),
+        }
+    }
+}
+
+/// Current unix time in seconds
+pub fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(byte: u8) -> UInt256 {
+        UInt256::from([byte; 32])
+    }
+
+    #[test]
+    fn outcome_is_taken_once() {
+        let confirmations = Confirmations::default();
+        confirmations.insert(hash(1), now() + 60, Outcome::Expired);
+
+        assert!(confirmations.take(&hash(2)).is_none());
+        assert!(matches!(
+            confirmations.take(&hash(1)),
+            Some(Outcome::Expired)
+        ));
+        assert!(confirmations.take(&hash(1)).is_none());
+    }
+
+    #[test]
+    fn clones_share_outcomes() {
+        let confirmations = Confirmations::default();
+        confirmations
+            .clone()
+            .insert(hash(1), now() + 60, Outcome::Sent(None));
+
+        assert!(matches!(
+            confirmations.take(&hash(1)),
+            Some(Outcome::Sent(None))
+        ));
+    }
+
+    #[test]
+    fn stale_outcomes_are_dropped() {
+        let confirmations = Confirmations::default();
+        let stale = now() - KEEP_OUTCOME_SECS - 1;
+        confirmations.insert(hash(1), stale, Outcome::Expired);
+        confirmations.insert(hash(2), now(), Outcome::Expired);
+
+        assert!(confirmations.take(&hash(1)).is_none());
+        assert!(confirmations.take(&hash(2)).is_some());
+    }
+}
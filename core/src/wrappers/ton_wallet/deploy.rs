@@ -0,0 +1,223 @@
+use std::sync::Arc;
+
+use ed25519_dalek::PublicKey;
+use nekoton::core::keystore::KeyStore;
+use nekoton::core::models::Expiration;
+use nekoton::core::ton_wallet::{ContractType, TonWallet};
+use nekoton::crypto::UnsignedMessage;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use ton_block::AccountState;
+
+use super::fees::{self, DeployFees};
+use super::multisig::check_signer;
+use super::retry::{RetryPolicy, SendAttempt};
+use super::{
+    fetch_state, parse_expiration, send_with_retries, Confirmations, SendError, SentMessage,
+    SignData,
+};
+use crate::{GqlTransport, TonWalletSubscription};
+
+/// Max number of custodians, supported by the multisig contracts
+const MAX_CUSTODIANS: usize = 32;
+
+#[derive(Deserialize)]
+pub struct DeployParams {
+    /// Hex encoded public keys of the custodians. Empty means the owner of the wallet only
+    #[serde(default)]
+    custodians: Vec<String>,
+    /// Number of custodians, which must confirm a transaction
+    #[serde(default = "default_req_confirms")]
+    req_confirms: u8,
+    /// Lifetime of the external message in seconds, one minute by default
+    timeout_secs: Option<u32>,
+}
+
+fn default_req_confirms() -> u8 {
+    1
+}
+
+/// Deployment of the wallet
+pub struct Deployment {
+    pub options: DeployOptions,
+    pub expiration: Expiration,
+}
+
+impl Deployment {
+    /// Deployment with the owner of the wallet only, made before the first transfer
+    pub fn single(expiration: Expiration) -> Self {
+        Self {
+            options: DeployOptions::Single,
+            expiration,
+        }
+    }
+}
+
+/// Initial owners of the wallet
+pub enum DeployOptions {
+    /// The owner of the wallet only
+    Single,
+    Multiple {
+        custodians: Vec<PublicKey>,
+        req_confirms: u8,
+    },
+}
+
+impl DeployParams {
+    /// Checks custodians and the threshold against the type of the wallet, and the timeout
+    pub fn parse(self, contract_type: ContractType) -> Result<Deployment, DeployParamsError> {
+        let expiration =
+            parse_expiration(self.timeout_secs).map_err(|_| DeployParamsError::InvalidTimeout)?;
+        let options = self.parse_options(contract_type)?;
+        Ok(Deployment {
+            options,
+            expiration,
+        })
+    }
+
+    fn parse_options(
+        self,
+        contract_type: ContractType,
+    ) -> Result<DeployOptions, DeployParamsError> {
+        if self.custodians.is_empty() {
+            return match self.req_confirms {
+                1 => Ok(DeployOptions::Single),
+                _ => Err(DeployParamsError::InvalidThreshold),
+            };
+        }
+        if !matches!(contract_type, ContractType::Multisig(_)) {
+            return Err(DeployParamsError::NotMultisig);
+        }
+        if self.custodians.len() > MAX_CUSTODIANS {
+            return Err(DeployParamsError::TooManyCustodians);
+        }
+        if self.req_confirms == 0 || self.req_confirms as usize > self.custodians.len() {
+            return Err(DeployParamsError::InvalidThreshold);
+        }
+
+        let mut custodians = Vec::with_capacity(self.custodians.len());
+        for custodian in self.custodians.iter() {
+            let custodian = hex::decode(custodian)
+                .ok()
+                .and_then(|key| PublicKey::from_bytes(&key).ok())
+                .ok_or(DeployParamsError::InvalidCustodian)?;
+            if custodians.contains(&custodian) {
+                return Err(DeployParamsError::DuplicateCustodian);
+            }
+            custodians.push(custodian);
+        }
+        Ok(DeployOptions::Multiple {
+            custodians,
+            req_confirms: self.req_confirms,
+        })
+    }
+}
+
+/// Progress of `deploy_wallet`, posted before the final reply
+#[derive(Serialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum DeployProgress {
+    /// Estimated fees, reported before anything is sent
+    Fees(DeployFees),
+    Attempt(SendAttempt),
+}
+
+pub fn prepare_deploy(
+    ton_wallet: &TonWallet,
+    deployment: &Deployment,
+) -> Result<Box<dyn UnsignedMessage>, SendError> {
+    let expiration = deployment.expiration;
+    Ok(match &deployment.options {
+        DeployOptions::Single => ton_wallet.prepare_deploy(expiration)?,
+        DeployOptions::Multiple {
+            custodians,
+            req_confirms,
+        } => {
+            ton_wallet.prepare_deploy_with_multiple_owners(expiration, custodians, *req_confirms)?
+        }
+    })
+}
+
+pub async fn deploy<R>(
+    keystore: &Mutex<KeyStore>,
+    keystore_type: &SignData,
+    wallet: &mut TonWallet,
+    confirmations: &Confirmations,
+    deployment: &Deployment,
+    policy: &RetryPolicy,
+    report: &R,
+) -> Result<SentMessage, SendError>
+where
+    R: Fn(SendAttempt) + Sync,
+{
+    let mut deploy = prepare_deploy(wallet, deployment)?;
+
+    send_with_retries(
+        keystore,
+        keystore_type,
+        wallet,
+        confirmations,
+        &mut deploy,
+        policy,
+        report,
+    )
+    .await
+}
+
+/// Deploys the wallet, which must have enough balance, after reporting estimated fees
+pub async fn deploy_wallet<R>(
+    keystore: Arc<Mutex<KeyStore>>,
+    keystore_type: SignData,
+    deployment: Deployment,
+    ton_wallet: Arc<TonWalletSubscription>,
+    transport: Arc<GqlTransport>,
+    policy: RetryPolicy,
+    report: R,
+) -> Result<SentMessage, SendError>
+where
+    R: Fn(DeployProgress) + Sync,
+{
+    check_signer(&keystore, &keystore_type, ton_wallet.inner.public_key()).await?;
+    let confirmations = ton_wallet.confirmations.clone();
+    let mut ton_wallet = ton_wallet.inner.clone();
+
+    let state = fetch_state(transport.inner.as_ref(), ton_wallet.address()).await?;
+    if let AccountState::AccountActive(_) = state.storage.state {
+        return Err(SendError::AlreadyDeployed);
+    }
+    let fees =
+        fees::estimate_deploy_fees(&ton_wallet, transport.inner.as_ref(), state, &deployment)
+            .await?;
+    let sufficient = fees.sufficient;
+    report(DeployProgress::Fees(fees));
+    if !sufficient {
+        return Err(SendError::InsufficientFunds);
+    }
+
+    deploy(
+        &keystore,
+        &keystore_type,
+        &mut ton_wallet,
+        &confirmations,
+        &deployment,
+        &policy,
+        &|attempt: SendAttempt| report(DeployProgress::Attempt(attempt)),
+    )
+    .await
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DeployParamsError {
+    #[error("Only multisig wallets can have several custodians")]
+    NotMultisig,
+    #[error("Too many custodians")]
+    TooManyCustodians,
+    #[error("Invalid custodian public key")]
+    InvalidCustodian,
+    #[error("Custodian is listed twice")]
+    DuplicateCustodian,
+    #[error("Required confirmations must be between 1 and the number of custodians")]
+    InvalidThreshold,
+    #[error("Timeout must be between 1 and 3600 seconds")]
+    InvalidTimeout,
+}
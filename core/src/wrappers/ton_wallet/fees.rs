@@ -0,0 +1,220 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nekoton::core::ton_wallet::{TonWallet, TransferAction};
+use nekoton::crypto::UnsignedMessage;
+use nekoton::transport::Transport;
+use serde::Serialize;
+use ton_block::{
+    Account, AccountStuff, Deserializable, Serializable, TrComputePhase, TransactionDescr,
+};
+use ton_executor::{BlockchainConfig, OrdinaryTransactionExecutor, TransactionExecutor};
+use ton_types::Cell;
+
+use super::batch::prepare_chunk;
+use super::deploy::{prepare_deploy, Deployment};
+use super::{fetch_state, SendError, Transfer};
+
+/// Fees of the single transaction, in nanotons
+#[derive(Serialize)]
+pub struct TransactionFees {
+    /// Fees of the outgoing messages
+    pub forward: u64,
+    pub storage: u64,
+    pub compute: u64,
+    /// Every fee of the transaction, including import of the external message
+    pub total: u64,
+    /// Whether the transaction would be aborted, its fees are charged anyway
+    pub aborted: bool,
+}
+
+#[derive(Serialize)]
+pub struct Fees {
+    /// Fees of the wallet deployment, made before the first transfer
+    pub deploy: Option<TransactionFees>,
+    pub transfer: TransactionFees,
+    /// Fees of both transactions
+    pub total_fees: u64,
+    pub balance: u64,
+    /// Whether the balance covers the amount and the fees and no transaction is aborted.
+    /// `send` fails with `InsufficientFunds` otherwise
+    pub sufficient: bool,
+}
+
+/// Runs transactions of the wallet on the local copy of its account
+struct LocalExecutor {
+    executor: OrdinaryTransactionExecutor,
+    account: Cell,
+    utime: u32,
+    last_trans_lt: Arc<AtomicU64>,
+}
+
+impl LocalExecutor {
+    fn new(state: AccountStuff, config: BlockchainConfig) -> Result<Self, SendError> {
+        let last_trans_lt = state.storage.last_trans_lt;
+        let account = Account::Account(state)
+            .serialize()
+            .map_err(|e| SendError::EstimationError(e.to_string()))?;
+
+        let mut executor = OrdinaryTransactionExecutor::new(config);
+        executor.set_signature_check_disabled(true);
+
+        let utime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as u32)
+            .unwrap_or_default();
+        Ok(Self {
+            executor,
+            account,
+            utime,
+            last_trans_lt: Arc::new(AtomicU64::new(last_trans_lt)),
+        })
+    }
+
+    /// Executes `message` signed with a dummy signature, updating the account
+    fn execute(&mut self, message: &dyn UnsignedMessage) -> Result<TransactionFees, SendError> {
+        let message = message
+            .sign(&[0; 64])
+            .map_err(|e| SendError::EstimationError(e.to_string()))?
+            .message;
+        let block_lt = self.last_trans_lt.load(Ordering::Acquire) + 1;
+        let transaction = self
+            .executor
+            .execute(
+                Some(&message),
+                &mut self.account,
+                self.utime,
+                block_lt,
+                self.last_trans_lt.clone(),
+                false,
+            )
+            .map_err(|e| SendError::EstimationError(e.to_string()))?;
+
+        let description = match transaction.read_description() {
+            Ok(TransactionDescr::Ordinary(description)) => description,
+            _ => {
+                return Err(SendError::EstimationError(
+                    "Unexpected transaction type".to_string(),
+                ))
+            }
+        };
+        Ok(TransactionFees {
+            forward: description
+                .action
+                .as_ref()
+                .and_then(|action| action.total_fwd_fees.as_ref())
+                .map_or(0, |fees| fees.0 as u64),
+            storage: description
+                .storage_ph
+                .as_ref()
+                .map_or(0, |phase| phase.storage_fees_collected.0 as u64),
+            compute: match &description.compute_ph {
+                TrComputePhase::Vm(phase) => phase.gas_fees.0 as u64,
+                TrComputePhase::Skipped(_) => 0,
+            },
+            total: transaction.total_fees().grams.0 as u64,
+            aborted: description.aborted,
+        })
+    }
+
+    fn state(&self) -> Result<AccountStuff, SendError> {
+        match Account::construct_from_cell(self.account.clone()) {
+            Ok(Account::Account(state)) => Ok(state),
+            Ok(Account::AccountNone) => Err(SendError::ContractDoesntExist),
+            Err(e) => Err(SendError::EstimationError(e.to_string())),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeployFees {
+    pub fees: TransactionFees,
+    pub balance: u64,
+    /// Whether the balance covers the fees
+    pub sufficient: bool,
+}
+
+/// Current config of the network, fees depend on it
+async fn fetch_config(transport: &dyn Transport) -> Result<BlockchainConfig, SendError> {
+    transport
+        .get_blockchain_config()
+        .await
+        .map_err(|e| SendError::TransportError(e.to_string()))
+}
+
+/// Executes deployment of the wallet with `state` locally
+pub async fn estimate_deploy_fees(
+    ton_wallet: &TonWallet,
+    transport: &dyn Transport,
+    state: AccountStuff,
+    deployment: &Deployment,
+) -> Result<DeployFees, SendError> {
+    let balance = state.storage.balance.grams.0 as u64;
+    let deploy = prepare_deploy(ton_wallet, deployment)?;
+    let config = fetch_config(transport).await?;
+    let fees = LocalExecutor::new(state, config)?.execute(deploy.as_ref())?;
+    Ok(DeployFees {
+        sufficient: fees.total <= balance && !fees.aborted,
+        fees,
+        balance,
+    })
+}
+
+/// Builds the same messages as `send` does, including the deployment, and executes them locally.
+/// Several `transfers` are paid by the single message, as `send_batch` does. They are never empty
+pub async fn estimate_fees(
+    mut ton_wallet: TonWallet,
+    transport: &dyn Transport,
+    transfers: &[Transfer],
+) -> Result<Fees, SendError> {
+    let state = fetch_state(transport, ton_wallet.address()).await?;
+    let balance = state.storage.balance.grams.0 as u64;
+    let config = fetch_config(transport).await?;
+    let mut executor = LocalExecutor::new(state.clone(), config)?;
+
+    let (deploy, message) = match prepare_chunk(&mut ton_wallet, &state, transfers)? {
+        TransferAction::Sign(message) => (None, message),
+        TransferAction::DeployFirst => {
+            let deploy = prepare_deploy(&ton_wallet, &Deployment::single(transfers[0].expiration))?;
+            let deploy = executor.execute(deploy.as_ref())?;
+            match prepare_chunk(&mut ton_wallet, &executor.state()?, transfers)? {
+                TransferAction::Sign(message) => (Some(deploy), message),
+                TransferAction::DeployFirst => return Err(SendError::DeployError),
+            }
+        }
+    };
+    let transfer_fees = executor.execute(message.as_ref())?;
+
+    let amount = transfers.iter().fold(0, |amount: u64, transfer| {
+        amount.saturating_add(transfer.amount)
+    });
+    let total_fees = transfer_fees.total + deploy.as_ref().map_or(0, |deploy| deploy.total);
+    let aborted = transfer_fees.aborted || deploy.as_ref().map_or(false, |deploy| deploy.aborted);
+    Ok(Fees {
+        deploy,
+        transfer: transfer_fees,
+        total_fees,
+        balance,
+        sufficient: amount.saturating_add(total_fees) <= balance && !aborted,
+    })
+}
+
+/// Refuses sending `transfers`, unless the balance covers them with fees
+pub async fn check_funds(
+    ton_wallet: &TonWallet,
+    transport: &dyn Transport,
+    transfers: &[Transfer],
+) -> Result<(), SendError> {
+    let fees = estimate_fees(ton_wallet.clone(), transport, transfers).await?;
+    if !fees.sufficient {
+        log::error!(
+            "Balance {} doesn't cover {} transfers and fees {}",
+            fees.balance,
+            transfers.len(),
+            fees.total_fees
+        );
+        return Err(SendError::InsufficientFunds);
+    }
+    Ok(())
+}
@@ -1,80 +1,534 @@
-use std::os::raw::{c_char, c_longlong};
+use std::fmt::Display;
+use std::future::Future;
+use std::os::raw::{c_char, c_longlong, c_uint};
 use std::str::FromStr;
 use std::sync::Arc;
 
+use nekoton::core::keystore::KeyStore;
+use nekoton::core::models::Expiration;
+use serde::Serialize;
+use tokio::sync::Mutex;
 use ton_block::MsgAddressInt;
 
 use crate::context::Context;
-use crate::ffi::StringResult;
-use crate::wrappers::ton_wallet::{send_inner, SignData};
-use crate::ExitCode;
-use crate::{cstr_to_string, get_runtime, ok_or_ret};
+use crate::ffi::{new_request, AsyncReply, ReplyErrorCode, SendPort};
+use crate::global::CONTEXTS;
+use crate::handles::Handle;
+use crate::panic::catch_panic;
+use crate::wrappers::ton_wallet::batch::{self, BatchParams, BatchProgress};
+use crate::wrappers::ton_wallet::deploy::{
+    deploy_wallet as deploy_wallet_inner, DeployParams, DeployProgress,
+};
+use crate::wrappers::ton_wallet::fees;
+use crate::wrappers::ton_wallet::multisig::{self, Confirmation};
+use crate::wrappers::ton_wallet::retry::{RetryPolicy, SendAttempt};
+use crate::wrappers::ton_wallet::{
+    parse_expiration, send_inner, SendError, SignData, Transfer, TransferParams,
+    TransferParamsError,
+};
+use crate::{cstr_to_string, ffi_ensure, get_handle, ok_or_ret, read_public_key};
+use crate::{ExitCode, GqlTransport, TonWalletSubscription};
 
+/// Sends `amount` to `to`, posting [`crate::ffi::AsyncReply`] with hash and expiration of the sent message
+/// and id of the resulting transaction to `answer_port`. Outcome of every attempt is posted as progress,
+/// see `set_retry_policy`. `sign_data` must select the key of the wallet owner, otherwise
+/// `WrongSigner` is replied.
 #[no_mangle]
 pub unsafe extern "C" fn send(
-    ctx: *mut Context,
-    sign_data: *mut c_char,
+    ctx: Handle,
+    sign_data: *const c_char,
     answer_port: c_longlong,
-    comment: *mut c_char,
-    to: *mut c_char,
+    comment: *const c_char,
+    to: *const c_char,
     amount: libc::c_ulonglong,
+    request_id: *mut u64,
 ) -> ExitCode {
-    if ctx.is_null() {
-        return ExitCode::NoContextProvided;
-    }
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, ctx, ExitCode::NoContextProvided);
 
-    if sign_data.is_null() {
-        return ExitCode::BadSignData;
-    }
-    let context = Box::from_raw(ctx).into();
-    let comment = if comment.is_null() {
-        None
-    } else {
-        Some(cstr_to_string!(comment, ExitCode::BadComment))
-    };
-    if to.is_null() {
-        return ExitCode::BadAddress;
-    }
-    let sign_data = cstr_to_string!(sign_data, ExitCode::BadSignData);
-    let sign_data: SignData = ok_or_ret!(serde_json::from_str(&sign_data), ExitCode::BadSignData);
-    let to = cstr_to_string!(to, ExitCode::BadAddress);
-    let to = ok_or_ret!(MsgAddressInt::from_str(&to), ExitCode::BadAddress);
+        ffi_ensure!(
+            sign_data.is_null(),
+            ExitCode::BadSignData,
+            "Sign data is null"
+        );
+        let comment = if comment.is_null() {
+            None
+        } else {
+            Some(cstr_to_string!(comment, ExitCode::BadComment))
+        };
+        ffi_ensure!(to.is_null(), ExitCode::BadAddress, "Address is null");
+        let sign_data = cstr_to_string!(sign_data, ExitCode::BadSignData);
+        let sign_data: SignData =
+            ok_or_ret!(serde_json::from_str(&sign_data), ExitCode::BadSignData);
+        let to = cstr_to_string!(to, ExitCode::BadAddress);
+        let to = ok_or_ret!(MsgAddressInt::from_str(&to), ExitCode::BadAddress);
 
-    send_ffi(answer_port, sign_data, to, amount, comment, context)
+        let transfer = Transfer::new(to, amount, comment);
+        send_ffi(&context, sign_data, answer_port, request_id, transfer)
+    })
 }
 
-fn send_ffi(
-    port: c_longlong,
-    keystore_type: SignData,
-    to: MsgAddressInt,
-    amount: u64,
-    comment: Option<String>,
-    context: Arc<Context>,
+/// Same as `send`, but takes optional parameters of the transfer as `params` json:
+/// `{"comment": "..", "payload": "base64 encoded BOC of the body", "bounce": false, "timeout_secs": 60}`.
+/// `comment` and `payload` are mutually exclusive
+#[no_mangle]
+pub unsafe extern "C" fn send_with_params(
+    ctx: Handle,
+    sign_data: *const c_char,
+    answer_port: c_longlong,
+    to: *const c_char,
+    amount: libc::c_ulonglong,
+    params: *const c_char,
+    request_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, ctx, ExitCode::NoContextProvided);
+
+        let sign_data = cstr_to_string!(sign_data, ExitCode::BadSignData);
+        let sign_data: SignData =
+            ok_or_ret!(serde_json::from_str(&sign_data), ExitCode::BadSignData);
+        let to = cstr_to_string!(to, ExitCode::BadAddress);
+        let to = ok_or_ret!(MsgAddressInt::from_str(&to), ExitCode::BadAddress);
+        let params = cstr_to_string!(params, ExitCode::BadTransferParams);
+        let params: TransferParams =
+            ok_or_ret!(serde_json::from_str(&params), ExitCode::BadTransferParams);
+        let transfer = ok_or_ret!(params.parse(to, amount), ExitCode::BadTransferParams);
+
+        send_ffi(&context, sign_data, answer_port, request_id, transfer)
+    })
+}
+
+/// Pays several recipients, grouping them into as few external messages as the wallet allows:
+/// up to four for WalletV3, one for multisig wallets. Messages are sent one after another.
+/// `params` is json `{"recipients": [{"to": "..", "amount": .., "comment": "..", "payload": "..",
+/// "bounce": false}, ..], "timeout_secs": 60}`, where `comment`, `payload` and `bounce` are optional.
+/// Posts [`crate::ffi::AsyncReply`] with the list of sent messages, as `send` does, to `answer_port`.
+/// Sending stops at the first failure, messages sent before it are the `payload` of the error reply.
+/// Outcome of every attempt and every sent message is posted as progress.
+/// `sign_data` must select the key of the wallet owner, as for `send`.
+#[no_mangle]
+pub unsafe extern "C" fn send_batch(
+    ctx: Handle,
+    sign_data: *const c_char,
+    answer_port: c_longlong,
+    params: *const c_char,
+    request_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, ctx, ExitCode::NoContextProvided);
+
+        let sign_data = cstr_to_string!(sign_data, ExitCode::BadSignData);
+        let sign_data: SignData =
+            ok_or_ret!(serde_json::from_str(&sign_data), ExitCode::BadSignData);
+        let params = cstr_to_string!(params, ExitCode::BadTransferParams);
+        let params: BatchParams =
+            ok_or_ret!(serde_json::from_str(&params), ExitCode::BadTransferParams);
+        let transfers = ok_or_ret!(params.parse(), ExitCode::BadTransferParams);
+
+        spawn_send(
+            &context,
+            "send_batch",
+            sign_data,
+            answer_port,
+            request_id,
+            move |args, report| async move {
+                batch::send_batch(
+                    args.keystore,
+                    args.keystore_type,
+                    transfers,
+                    args.wallet,
+                    args.transport,
+                    args.policy,
+                    move |progress: BatchProgress| report.post(progress),
+                )
+                .await
+            },
+        )
+    })
+}
+
+unsafe fn send_ffi(
+    context: &Context,
+    sign_data: SignData,
+    answer_port: c_longlong,
+    request_id: *mut u64,
+    transfer: Transfer,
 ) -> ExitCode {
-    let _rt = get_runtime!().enter();
-    let (keystore, wallet, transport) = (
-        context.keystore.clone(),
+    spawn_send(
+        context,
+        "send",
+        sign_data,
+        answer_port,
+        request_id,
+        move |args, report| async move {
+            send_inner(
+                args.keystore,
+                args.keystore_type,
+                transfer,
+                args.wallet,
+                args.transport,
+                args.policy,
+                move |attempt: SendAttempt| report.post(attempt),
+            )
+            .await
+        },
+    )
+}
+
+/// Parts of the context, used by sending requests, with the resolved sign data
+struct SendArgs {
+    keystore: Arc<Mutex<KeyStore>>,
+    keystore_type: SignData,
+    wallet: Arc<TonWalletSubscription>,
+    transport: Arc<GqlTransport>,
+    policy: RetryPolicy,
+}
+
+/// Posts progress of the request as [`crate::ffi::AsyncReply`]
+#[derive(Copy, Clone)]
+struct Progress {
+    request_id: u64,
+    port: SendPort,
+}
+
+impl Progress {
+    fn post<T: Serialize>(&self, progress: T) {
+        AsyncReply::progress(self.request_id, progress).post(&self.port);
+    }
+}
+
+/// Spawns sending request `name` of the context, posting [`crate::ffi::AsyncReply`] with its result
+/// to `answer_port`. Unlock session of `sign_data` is resolved before `send` is called.
+/// Id of the request is written into `request_id`, unless it's null
+unsafe fn spawn_send<S, F, T, E>(
+    context: &Context,
+    name: &'static str,
+    sign_data: SignData,
+    answer_port: c_longlong,
+    request_id: *mut u64,
+    send: S,
+) -> ExitCode
+where
+    S: FnOnce(SendArgs, Progress) -> F + Send + 'static,
+    F: Future<Output = Result<T, E>> + Send,
+    T: Serialize + Send + 'static,
+    E: From<SendError> + ReplyErrorCode + Display + Send + 'static,
+{
+    let request_id = new_request(request_id);
+    let port = SendPort::new(answer_port);
+    let report = Progress { request_id, port };
+    let (keystore, wallet, transport, sessions, policy) = (
+        context.keystore.inner().clone(),
         context.wallet_state.clone(),
         context.transport.clone(),
+        context.keystore.sessions().clone(),
+        context.retry_policy(),
     );
 
-    context.spawn(async move {
-        let res = send_inner(
-            keystore,
-            keystore_type,
-            to,
-            amount,
-            wallet,
-            transport,
-            comment,
+    context
+        .manager
+        .spawn_request(name, request_id, port, async move {
+            let keystore_type = sessions.resolve(sign_data).await.map_err(|e| {
+                log::error!("Failed resolving sign data: {}", e);
+                SendError::InvalidPassword
+            })?;
+            let args = SendArgs {
+                keystore,
+                keystore_type,
+                wallet,
+                transport,
+                policy,
+            };
+            send(args, report).await
+        })
+}
+
+/// Estimates fees of sending `amount` to `to`, including the wallet deployment if it's needed.
+/// `params` is json of the transfer parameters, the same as of `send_with_params`.
+/// Posts [`crate::ffi::AsyncReply`] with fees breakdown, balance and whether it's sufficient to `answer_port`.
+/// Id of the request is written into `request_id`, unless it's null
+#[no_mangle]
+pub unsafe extern "C" fn estimate_fees(
+    ctx: Handle,
+    answer_port: c_longlong,
+    to: *const c_char,
+    amount: libc::c_ulonglong,
+    params: *const c_char,
+    request_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, ctx, ExitCode::NoContextProvided);
+
+        let to = cstr_to_string!(to, ExitCode::BadAddress);
+        let to = ok_or_ret!(MsgAddressInt::from_str(&to), ExitCode::BadAddress);
+        let params = cstr_to_string!(params, ExitCode::BadTransferParams);
+        let params: TransferParams =
+            ok_or_ret!(serde_json::from_str(&params), ExitCode::BadTransferParams);
+        let transfer = ok_or_ret!(params.parse(to, amount), ExitCode::BadTransferParams);
+
+        let request_id = new_request(request_id);
+        let (wallet, transport) = (
+            context.wallet_state.inner.clone(),
+            context.transport.inner.clone(),
+        );
+        context.manager.spawn_request(
+            "estimate_fees",
+            request_id,
+            SendPort::new(answer_port),
+            async move {
+                fees::estimate_fees(wallet, transport.as_ref(), std::slice::from_ref(&transfer))
+                    .await
+            },
         )
-        .await;
-        let data = match res {
-            Ok(_) => StringResult::Ok("".into()),
-            Err(e) => StringResult::Error(e.to_string()),
+    })
+}
+
+/// Deploys the wallet, posting [`crate::ffi::AsyncReply`] with hash and expiration of the sent message
+/// and id of the resulting transaction to `answer_port`. Estimated fees are posted as progress before
+/// anything is sent, followed by outcome of every attempt.
+/// `params` is json `{"custodians": ["hex public key", ..], "req_confirms": .., "timeout_secs": 60}`,
+/// all fields are optional. Custodians are allowed for multisig wallets only. Null `params` deploys
+/// the wallet with its owner as the only custodian. `sign_data` must select the key of the owner,
+/// as for `send`.
+#[no_mangle]
+pub unsafe extern "C" fn deploy_wallet(
+    ctx: Handle,
+    sign_data: *const c_char,
+    params: *const c_char,
+    answer_port: c_longlong,
+    request_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, ctx, ExitCode::NoContextProvided);
+
+        let sign_data = cstr_to_string!(sign_data, ExitCode::BadSignData);
+        let sign_data: SignData =
+            ok_or_ret!(serde_json::from_str(&sign_data), ExitCode::BadSignData);
+        let params = if params.is_null() {
+            "{}".to_string()
+        } else {
+            cstr_to_string!(params, ExitCode::BadDeployParams)
         };
-        let port = crate::ffi::SendPort::new(port);
-        port.post(serde_json::to_string(&data).unwrap());
-    });
-    ExitCode::Ok
+        let params: DeployParams =
+            ok_or_ret!(serde_json::from_str(&params), ExitCode::BadDeployParams);
+        let deployment = ok_or_ret!(
+            params.parse(context.wallet_state.inner.contract_type()),
+            ExitCode::BadDeployParams
+        );
+
+        spawn_send(
+            &context,
+            "deploy_wallet",
+            sign_data,
+            answer_port,
+            request_id,
+            move |args, report| async move {
+                deploy_wallet_inner(
+                    args.keystore,
+                    args.keystore_type,
+                    deployment,
+                    args.wallet,
+                    args.transport,
+                    args.policy,
+                    move |progress: DeployProgress| report.post(progress),
+                )
+                .await
+            },
+        )
+    })
+}
+
+/// Posts [`crate::ffi::AsyncReply`] with custodians of the multisig, `[{"index": .., "public_key": ".."}]`,
+/// to `answer_port`. Id of the request is written into `request_id`, unless it's null
+#[no_mangle]
+pub unsafe extern "C" fn get_multisig_custodians(
+    ctx: Handle,
+    answer_port: c_longlong,
+    request_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, ctx, ExitCode::NoContextProvided);
+        ffi_ensure!(
+            multisig::multisig_abi(&context.wallet_state.inner).is_none(),
+            ExitCode::NotMultisig,
+            "Wallet is not a multisig"
+        );
+
+        let request_id = new_request(request_id);
+        let (wallet, transport) = (
+            context.wallet_state.inner.clone(),
+            context.transport.inner.clone(),
+        );
+        context.manager.spawn_request(
+            "get_multisig_custodians",
+            request_id,
+            SendPort::new(answer_port),
+            async move { multisig::get_custodians(&wallet, transport.as_ref()).await },
+        )
+    })
+}
+
+/// Posts [`crate::ffi::AsyncReply`] with transactions of the multisig, which wait for confirmations,
+/// to `answer_port`. New ones are also posted to the subscription port as they appear.
+/// Id of the request is written into `request_id`, unless it's null
+#[no_mangle]
+pub unsafe extern "C" fn get_multisig_pending_transactions(
+    ctx: Handle,
+    answer_port: c_longlong,
+    request_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, ctx, ExitCode::NoContextProvided);
+        ffi_ensure!(
+            multisig::multisig_abi(&context.wallet_state.inner).is_none(),
+            ExitCode::NotMultisig,
+            "Wallet is not a multisig"
+        );
+
+        let request_id = new_request(request_id);
+        let (wallet, transport) = (
+            context.wallet_state.inner.clone(),
+            context.transport.inner.clone(),
+        );
+        context.manager.spawn_request(
+            "get_multisig_pending_transactions",
+            request_id,
+            SendPort::new(answer_port),
+            async move { multisig::get_pending_transactions(&wallet, transport.as_ref()).await },
+        )
+    })
+}
+
+/// Submits transfer of `amount` to `to` from the multisig on behalf of the wallet owner, whose key
+/// must be selected by `sign_data`, otherwise `WrongSigner` is replied. If the wallet requires
+/// several confirmations, the transfer stays pending until other custodians confirm it.
+/// Posts [`crate::ffi::AsyncReply`] with the sent message, as `send` does, and `pending_id`
+/// of the created transaction to `answer_port`. Outcome of every attempt is posted as progress.
+#[no_mangle]
+pub unsafe extern "C" fn submit_transaction(
+    ctx: Handle,
+    sign_data: *const c_char,
+    answer_port: c_longlong,
+    comment: *const c_char,
+    to: *const c_char,
+    amount: libc::c_ulonglong,
+    request_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, ctx, ExitCode::NoContextProvided);
+        ffi_ensure!(
+            multisig::multisig_abi(&context.wallet_state.inner).is_none(),
+            ExitCode::NotMultisig,
+            "Wallet is not a multisig"
+        );
+
+        let sign_data = cstr_to_string!(sign_data, ExitCode::BadSignData);
+        let sign_data: SignData =
+            ok_or_ret!(serde_json::from_str(&sign_data), ExitCode::BadSignData);
+        let comment = if comment.is_null() {
+            None
+        } else {
+            Some(cstr_to_string!(comment, ExitCode::BadComment))
+        };
+        let to = cstr_to_string!(to, ExitCode::BadAddress);
+        let to = ok_or_ret!(MsgAddressInt::from_str(&to), ExitCode::BadAddress);
+        let transfer = Transfer::new(to, amount, comment);
+
+        spawn_send(
+            &context,
+            "submit_transaction",
+            sign_data,
+            answer_port,
+            request_id,
+            move |args, report| async move {
+                multisig::submit_transaction(
+                    args.keystore,
+                    args.keystore_type,
+                    transfer,
+                    args.wallet,
+                    args.transport,
+                    args.policy,
+                    move |progress: SendAttempt| report.post(progress),
+                )
+                .await
+            },
+        )
+    })
+}
+
+/// Confirms pending transaction `transaction_id` of the multisig on behalf of `custodian`
+/// (hex encoded public key), whose key must be selected by `sign_data`, otherwise `WrongSigner` is
+/// replied. `timeout_secs` is the lifetime of the external message, `0` for the default one minute.
+/// Posts [`crate::ffi::AsyncReply`] with the sent message, as `send` does, to `answer_port`.
+/// Outcome of every attempt is posted as progress.
+#[no_mangle]
+pub unsafe extern "C" fn confirm_transaction(
+    ctx: Handle,
+    sign_data: *const c_char,
+    custodian: *const c_char,
+    transaction_id: u64,
+    timeout_secs: c_uint,
+    answer_port: c_longlong,
+    request_id: *mut u64,
+) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, ctx, ExitCode::NoContextProvided);
+        ffi_ensure!(
+            multisig::multisig_abi(&context.wallet_state.inner).is_none(),
+            ExitCode::NotMultisig,
+            "Wallet is not a multisig"
+        );
+
+        let sign_data = cstr_to_string!(sign_data, ExitCode::BadSignData);
+        let sign_data: SignData =
+            ok_or_ret!(serde_json::from_str(&sign_data), ExitCode::BadSignData);
+        let custodian = ok_or_ret!(read_public_key(custodian), ExitCode::InvalidPublicKey);
+        let confirmation = Confirmation {
+            custodian,
+            transaction_id,
+            expiration: ok_or_ret!(read_timeout(timeout_secs), ExitCode::BadTransferParams),
+        };
+
+        spawn_send(
+            &context,
+            "confirm_transaction",
+            sign_data,
+            answer_port,
+            request_id,
+            move |args, report| async move {
+                multisig::confirm_transaction(
+                    args.keystore,
+                    args.keystore_type,
+                    confirmation,
+                    args.wallet,
+                    args.transport,
+                    args.policy,
+                    move |progress: SendAttempt| report.post(progress),
+                )
+                .await
+            },
+        )
+    })
+}
+
+/// Expiration of the external message, which lives `timeout_secs`, or a minute if it's `0`
+fn read_timeout(timeout_secs: c_uint) -> Result<Expiration, TransferParamsError> {
+    parse_expiration(Some(timeout_secs).filter(|&timeout| timeout != 0))
+}
+
+/// Sets policy of re-sending messages of the `context`, which failed to be sent or expired.
+/// `policy` is json `{"max_attempts": .., "initial_backoff_ms": .., "max_backoff_ms": ..,
+/// "confirmation_timeout_secs": .., "retryable": ["TransportError", "Expired"]}`, omitted fields are defaults.
+/// Only these two codes are accepted: other failures may happen after the message was sent
+#[no_mangle]
+pub unsafe extern "C" fn set_retry_policy(ctx: Handle, policy: *const c_char) -> ExitCode {
+    catch_panic(|| {
+        let context = get_handle!(CONTEXTS, ctx, ExitCode::NoContextProvided);
+        let policy = cstr_to_string!(policy, ExitCode::BadRetryPolicy);
+        let policy: RetryPolicy =
+            ok_or_ret!(serde_json::from_str(&policy), ExitCode::BadRetryPolicy);
+        ok_or_ret!(policy.validate(), ExitCode::BadRetryPolicy);
+        context.set_retry_policy(policy);
+        ExitCode::Ok
+    })
 }
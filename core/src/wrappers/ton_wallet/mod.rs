@@ -1,144 +1,457 @@
 use std::sync::Arc;
 
 use nekoton::core::keystore::KeyStore;
-use nekoton::core::models::Expiration;
+use nekoton::core::models::{Expiration, Transaction, TransactionId};
 use nekoton::core::ton_wallet::TransferAction;
-use nekoton::crypto::{
-    DerivedKeySignParams, DerivedKeySigner, EncryptedKeyPassword, EncryptedKeySigner,
-    UnsignedMessage,
-};
+use nekoton::crypto::{DerivedKeySignParams, EncryptedKeyPassword, SignedMessage, UnsignedMessage};
 use nekoton::helpers::abi::create_comment_payload;
 use nekoton::transport::Transport;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use tokio::time::Duration;
-use ton_block::MsgAddressInt;
+use ton_block::{AccountStuff, MsgAddressInt};
+use ton_types::{Cell, SliceData};
 
+use crate::ffi::ReplyErrorCode;
 use crate::match_option;
-use crate::wrappers::ton_wallet::SendError::TransportError;
+use crate::wrappers::storage::session::UnlockedKey;
+use crate::wrappers::storage::signature;
 use crate::{GqlTransport, TonWalletSubscription};
 use tokio::sync::Mutex;
+mod batch;
+mod confirmation;
+mod deploy;
+mod fees;
 mod ffi;
+mod multisig;
+mod retry;
+pub use confirmation::{Confirmations, Outcome};
+use deploy::{deploy, Deployment};
 pub use ffi::send;
+use multisig::check_signer;
+pub use multisig::{MultisigTransaction, PendingTracker};
 use nekoton::transport::models::RawContractState;
+pub use retry::RetryPolicy;
+use retry::SendAttempt;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum SignData {
     Derived(DerivedKeySignParams),
     Encrypted(EncryptedKeyPassword),
+    /// Credentials of the unlock session with `id`, see `unlock_key`
+    Session {
+        id: u64,
+    },
+    /// Key of the session, resolved by [`crate::wrappers::storage::session::UnlockSessions`]
+    #[serde(skip)]
+    Unlocked(Arc<UnlockedKey>),
 }
 
-async fn send_inner(
+/// Max lifetime of the external message, which can be requested
+const MAX_TIMEOUT_SECS: u32 = 3600;
+
+/// Destination and value of the transfer
+pub struct Transfer {
+    pub to: MsgAddressInt,
+    pub amount: u64,
+    pub comment: Option<String>,
+    /// Body of the internal message, used instead of the comment
+    pub payload: Option<Cell>,
+    pub bounce: bool,
+    pub expiration: Expiration,
+}
+
+impl Transfer {
+    /// Plain transfer with an optional comment, which doesn't bounce and expires in a minute
+    pub fn new(to: MsgAddressInt, amount: u64, comment: Option<String>) -> Self {
+        Self {
+            to,
+            amount,
+            comment,
+            payload: None,
+            bounce: false,
+            expiration: Expiration::Timeout(60),
+        }
+    }
+
+    /// Body of the internal message
+    fn body(&self) -> Option<SliceData> {
+        match (&self.payload, &self.comment) {
+            (Some(payload), _) => Some(payload.clone().into()),
+            (None, Some(comment)) => match_option!(create_comment_payload(comment)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Optional parameters of the transfer, see `send_with_params`
+#[derive(Deserialize)]
+pub struct TransferParams {
+    comment: Option<String>,
+    /// Base64 encoded BOC of the message body, can't be combined with `comment`
+    payload: Option<String>,
+    #[serde(default)]
+    bounce: bool,
+    /// Lifetime of the external message in seconds, one minute by default
+    timeout_secs: Option<u32>,
+}
+
+impl TransferParams {
+    pub fn parse(self, to: MsgAddressInt, amount: u64) -> Result<Transfer, TransferParamsError> {
+        Ok(Transfer {
+            to,
+            amount,
+            payload: parse_payload(self.payload, &self.comment)?,
+            comment: self.comment,
+            bounce: self.bounce,
+            expiration: parse_expiration(self.timeout_secs)?,
+        })
+    }
+}
+
+/// Decodes base64 encoded BOC of the message body
+fn parse_payload(
+    payload: Option<String>,
+    comment: &Option<String>,
+) -> Result<Option<Cell>, TransferParamsError> {
+    let payload = match payload {
+        Some(_) if comment.is_some() => return Err(TransferParamsError::CommentWithPayload),
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+    let boc =
+        base64::decode(&payload).map_err(|e| TransferParamsError::InvalidPayload(e.to_string()))?;
+    ton_types::deserialize_tree_of_cells(&mut std::io::Cursor::new(boc))
+        .map(Some)
+        .map_err(|e| TransferParamsError::InvalidPayload(e.to_string()))
+}
+
+fn parse_expiration(timeout_secs: Option<u32>) -> Result<Expiration, TransferParamsError> {
+    match timeout_secs {
+        Some(timeout) if timeout == 0 || timeout > MAX_TIMEOUT_SECS => {
+            Err(TransferParamsError::InvalidTimeout)
+        }
+        Some(timeout) => Ok(Expiration::Timeout(timeout)),
+        None => Ok(Expiration::Timeout(60)),
+    }
+}
+
+/// Checks that `keystore_type` opens its key
+async fn check_password(keystore: &KeyStore, keystore_type: &SignData) -> Result<(), SendError> {
+    signature::check_password(keystore, keystore_type)
+        .await
+        .map_err(|e| {
+            log::error!("Failed checking password: {}", e);
+            SendError::InvalidPassword
+        })
+}
+
+async fn send_inner<R>(
     keystore: Arc<Mutex<KeyStore>>,
     keystore_type: SignData,
-    to: MsgAddressInt,
-    amount: u64,
+    transfer: Transfer,
     ton_wallet: Arc<TonWalletSubscription>,
     transport: Arc<GqlTransport>,
-    comment: Option<String>,
-) -> Result<(), SendError> {
+    policy: RetryPolicy,
+    report: R,
+) -> Result<SentMessage, SendError>
+where
+    R: Fn(SendAttempt) + Sync,
+{
+    check_signer(&keystore, &keystore_type, ton_wallet.inner.public_key()).await?;
+    let confirmations = ton_wallet.confirmations.clone();
     let mut ton_wallet = ton_wallet.inner.clone();
     let transport = transport.inner.clone();
-    tokio::runtime::Builder::new_current_thread();
-    let state = match transport.get_contract_state(ton_wallet.address()).await? {
-        RawContractState::NotExists => {
-            log::error!("Contract doesn't exist");
-            return Err(SendError::ContractDoesntExist); //todo should I deploy in this case?
+    fees::check_funds(
+        &ton_wallet,
+        transport.as_ref(),
+        std::slice::from_ref(&transfer),
+    )
+    .await?;
+    let state = fetch_state(transport.as_ref(), ton_wallet.address()).await?;
+    let mut message = match prepare_transfer(&mut ton_wallet, &state, &transfer)? {
+        TransferAction::Sign(message) => message,
+        TransferAction::DeployFirst => {
+            deploy(
+                &keystore,
+                &keystore_type,
+                &mut ton_wallet,
+                &confirmations,
+                &Deployment::single(transfer.expiration),
+                &policy,
+                &report,
+            )
+            .await?;
+            let state = fetch_state(transport.as_ref(), ton_wallet.address()).await?;
+            match prepare_transfer(&mut ton_wallet, &state, &transfer)? {
+                TransferAction::Sign(message) => message,
+                TransferAction::DeployFirst => return Err(SendError::DeployError),
+            }
         }
-        RawContractState::Exists(a) => a.account,
     };
 
-    let comment = comment
-        .map(|x| match_option!(create_comment_payload(&x)))
-        .flatten();
-    let prepare_transfer_data =
-        ton_wallet.prepare_transfer(&state, to, amount, false, comment, Expiration::Timeout(60))?;
-    let keystore = keystore.lock().await;
-    if let TransferAction::DeployFirst = prepare_transfer_data {
-        deploy(&keystore, &keystore_type, &mut ton_wallet).await?;
-    }
-    let mut message = match prepare_transfer_data {
-        TransferAction::DeployFirst => {
-            unreachable!("Really?") //todo checkme
+    send_with_retries(
+        &keystore,
+        &keystore_type,
+        &mut ton_wallet,
+        &confirmations,
+        &mut message,
+        &policy,
+        &report,
+    )
+    .await
+}
+
+/// Fetches state of the wallet, which must exist
+async fn fetch_state(
+    transport: &dyn Transport,
+    address: &MsgAddressInt,
+) -> Result<AccountStuff, SendError> {
+    match transport.get_contract_state(address).await? {
+        RawContractState::Exists(contract) => Ok(contract.account),
+        RawContractState::NotExists => {
+            log::error!("Contract doesn't exist");
+            Err(SendError::ContractDoesntExist)
         }
-        TransferAction::Sign(a) => a,
-    };
+    }
+}
 
-    //todo do it n times?
+/// Builds unsigned message of the `transfer` or tells, that the wallet must be deployed first
+fn prepare_transfer(
+    ton_wallet: &mut nekoton::core::ton_wallet::TonWallet,
+    state: &AccountStuff,
+    transfer: &Transfer,
+) -> Result<TransferAction, SendError> {
+    Ok(ton_wallet.prepare_transfer(
+        state,
+        transfer.to.clone(),
+        transfer.amount,
+        transfer.bounce,
+        transfer.body(),
+        transfer.expiration,
+    )?)
+}
+
+/// Signs and sends `message` until it's confirmed or `policy` gives up, reporting every attempt.
+///
+/// Message, which may have reached the network, is never replaced by a new one, unless it's known
+/// to be expired: the wallet could execute both. Failed sending is retried with the same signed
+/// message, which is executed at most once
+async fn send_with_retries<R>(
+    keystore: &Mutex<KeyStore>,
+    keystore_type: &SignData,
+    ton_wallet: &mut nekoton::core::ton_wallet::TonWallet,
+    confirmations: &Confirmations,
+    message: &mut Box<dyn UnsignedMessage>,
+    policy: &RetryPolicy,
+    report: &R,
+) -> Result<SentMessage, SendError>
+where
+    R: Fn(SendAttempt) + Sync,
+{
+    let mut attempt = 0;
+    let mut signed = None;
+    // Whether sending of the current signed message failed, so it may have been sent anyway
+    let mut maybe_sent = false;
     loop {
-        let res = tokio::time::timeout(
-            Duration::from_secs(60),
-            sign_and_send(&keystore, &keystore_type, &mut ton_wallet, &mut message),
+        attempt += 1;
+        let res = sign_and_send(
+            keystore,
+            keystore_type,
+            ton_wallet,
+            confirmations,
+            message,
+            &mut signed,
+            policy,
         )
         .await;
-        match res {
-            Ok(a) => {
-                if let Err(e) = a {
-                    log::error!("Failed sending: {}", e);
-                    continue;
-                } else {
-                    break;
-                }
+        let error = match res {
+            Ok(sent) => {
+                report(SendAttempt::new(attempt, policy, None, None));
+                return Ok(sent);
+            }
+            Err(SendError::TransportError(e)) if signed.is_some() => {
+                maybe_sent = true;
+                SendError::TransportError(e)
             }
-            Err(_) => continue,
+            Err(SendError::Expired) if maybe_sent => SendError::Unconfirmed(
+                "message expired, but an earlier attempt to send it may have succeeded".to_string(),
+            ),
+            Err(e) => e,
+        };
+
+        log::error!("Attempt {} failed: {}", attempt, error);
+        let backoff = policy
+            .backoff(attempt, &error)
+            .and_then(|delay| match &signed {
+                // The same message is sent again, while it's still valid
+                Some(signed) if matches!(error, SendError::TransportError(_)) => {
+                    let resend_at = confirmation::now() as u64 + delay.as_secs() + 1;
+                    if resend_at < signed.expire_at as u64 {
+                        Some(delay)
+                    } else {
+                        None
+                    }
+                }
+                // New message is signed only after the previous one expired
+                Some(signed) => {
+                    let expired_in = (signed.expire_at + 1).saturating_sub(confirmation::now());
+                    Some(delay.max(Duration::from_secs(expired_in as u64)))
+                }
+                None => Some(delay),
+            });
+        report(SendAttempt::new(attempt, policy, Some(&error), backoff));
+        match backoff {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => return Err(error),
+        }
+        if matches!(error, SendError::Expired) {
+            signed = None;
+            maybe_sent = false;
         }
     }
-    Ok(())
 }
 
-async fn get_balance(wallet: &mut nekoton::core::ton_wallet::TonWallet) -> Result<u64, SendError> {
-    wallet
-        .refresh()
-        .await
-        .map_err(|e| TransportError(e.to_string()))?;
-    Ok(wallet.contract_state().balance)
+/// Payload of the successful `send` reply
+#[derive(Serialize)]
+pub struct SentMessage {
+    /// Hex encoded hash of the sent external message
+    pub message_hash: String,
+    pub expire_at: u32,
+    pub transaction_id: Option<TransactionId>,
+    /// Transaction, which included the message. `null` if the transaction was found, but couldn't be parsed
+    pub transaction: Option<Transaction>,
 }
 
+/// Sends `signed` message, signing `message` first if there is none, and waits for its outcome
+/// until it expires and `policy` grace period passes
 async fn sign_and_send(
-    keystore: &KeyStore,
+    keystore: &Mutex<KeyStore>,
     keystore_type: &SignData,
     ton_wallet: &mut nekoton::core::ton_wallet::TonWallet,
+    confirmations: &Confirmations,
     message: &mut Box<dyn UnsignedMessage>,
-) -> Result<(), SendError> {
-    message.refresh_timeout();
-    let hash = message.hash();
-    let signature = match keystore_type {
-        SignData::Derived(a) => keystore.sign::<DerivedKeySigner>(hash, a.clone()).await,
-        SignData::Encrypted(a) => keystore.sign::<EncryptedKeySigner>(hash, a.clone()).await,
-    }
-    .map_err(|e| {
-        log::error!("Failed singing: {}", e);
-        SendError::SignError
-    })?;
-    let singed = message.sign(&signature).map_err(|e| {
-        log::error!("Failed signing: {}", e);
-        SendError::SignError
-    })?;
-    let initial_balance = get_balance(ton_wallet).await?;
-
-    ton_wallet
-        .send(&singed.message, singed.expire_at)
+    signed: &mut Option<SignedMessage>,
+    policy: &RetryPolicy,
+) -> Result<SentMessage, SendError> {
+    let signed = match signed {
+        Some(signed) => signed,
+        None => {
+            message.refresh_timeout();
+            let hash = message.hash();
+            // Keystore is locked for signing only, not while the message is awaited
+            let signature = signature::sign(&*keystore.lock().await, keystore_type, hash)
+                .await
+                .map_err(|e| {
+                    log::error!("Failed singing: {}", e);
+                    SendError::SignError
+                })?;
+            let new = message.sign(&signature).map_err(|e| {
+                log::error!("Failed signing: {}", e);
+                SendError::SignError
+            })?;
+            signed.get_or_insert(new)
+        }
+    };
+
+    let pending = ton_wallet
+        .send(&signed.message, signed.expire_at)
         .await
         .map_err(|e| SendError::TransportError(e.to_string()))?;
-    while initial_balance > get_balance(ton_wallet).await? {
+    let deadline = pending
+        .expire_at
+        .saturating_add(policy.confirmation_timeout_secs);
+    // Subscription handler resolves pending transaction, once the wallet is refreshed
+    // after the message is included or expired
+    let transaction = loop {
+        match confirmations.take(&pending.message_hash) {
+            Some(Outcome::Sent(transaction)) => break transaction,
+            Some(Outcome::Expired) => return Err(SendError::Expired),
+            None => {}
+        }
+        if confirmation::now() > deadline {
+            return Err(SendError::Timeout);
+        }
         tokio::time::sleep(Duration::from_secs(1)).await;
-    }
-    //todo check loop
-    Ok(())
+        // Message is sent already, so the failure leaves its outcome unknown
+        ton_wallet
+            .refresh()
+            .await
+            .map_err(|e| SendError::Unconfirmed(e.to_string()))?;
+    };
+
+    Ok(SentMessage {
+        message_hash: hex::encode(pending.message_hash.as_slice()),
+        expire_at: pending.expire_at,
+        transaction_id: transaction
+            .as_ref()
+            .map(|transaction| transaction.id.clone()),
+        transaction,
+    })
 }
 
 #[derive(Error, Debug)]
 pub enum SendError {
-    #[error("transport error")]
+    #[error("Transport error: {0}")]
     TransportError(String),
     #[error("Constract doesn't exist")]
     ContractDoesntExist,
     #[error("Sign error")]
     SignError,
+    #[error("Invalid password")]
+    InvalidPassword,
     #[error("Deploy error")]
     DeployError,
+    #[error("Message wasn't confirmed in time")]
+    Timeout,
+    #[error("Message was sent, but its outcome is unknown: {0}")]
+    Unconfirmed(String),
+    #[error("Message expired before it was included into a transaction")]
+    Expired,
+    #[error("Failed to estimate fees: {0}")]
+    EstimationError(String),
+    #[error("Wallet is already deployed")]
+    AlreadyDeployed,
+    #[error("Balance doesn't cover the fees")]
+    InsufficientFunds,
+    #[error("Wallet is not a multisig")]
+    NotMultisig,
+    #[error("Failed to run getter: {0}")]
+    GetterError(String),
+    #[error("Key is not a custodian of the wallet")]
+    NotCustodian,
+    #[error("Pending transaction not found")]
+    TransactionNotFound,
+    #[error("Transaction is already confirmed by the custodian")]
+    AlreadyConfirmed,
+    #[error("Sign data selects a key other than the signer of the message")]
+    WrongSigner,
+}
+
+impl ReplyErrorCode for SendError {
+    fn code(&self) -> &'static str {
+        match self {
+            SendError::TransportError(_) => "TransportError",
+            SendError::ContractDoesntExist => "ContractDoesntExist",
+            SendError::SignError => "SignError",
+            SendError::InvalidPassword => "InvalidPassword",
+            SendError::DeployError => "DeployError",
+            SendError::Timeout => "Timeout",
+            SendError::Unconfirmed(_) => "Unconfirmed",
+            SendError::Expired => "Expired",
+            SendError::EstimationError(_) => "EstimationError",
+            SendError::AlreadyDeployed => "AlreadyDeployed",
+            SendError::InsufficientFunds => "InsufficientFunds",
+            SendError::NotMultisig => "NotMultisig",
+            SendError::GetterError(_) => "GetterError",
+            SendError::NotCustodian => "NotCustodian",
+            SendError::TransactionNotFound => "TransactionNotFound",
+            SendError::AlreadyConfirmed => "AlreadyConfirmed",
+            SendError::WrongSigner => "WrongSigner",
+        }
+    }
 }
 
 impl From<anyhow::Error> for SendError {
@@ -147,12 +460,18 @@ impl From<anyhow::Error> for SendError {
     }
 }
 
-pub async fn deploy(
-    keystore: &KeyStore,
-    keystore_type: &SignData,
-    wallet: &mut nekoton::core::ton_wallet::TonWallet,
-) -> Result<(), SendError> {
-    let mut deploy = wallet.prepare_deploy(Expiration::Timeout(60))?;
-
-    sign_and_send(keystore, keystore_type, wallet, &mut deploy).await
+#[derive(Error, Debug)]
+pub enum TransferParamsError {
+    #[error("Comment and payload can't be sent together")]
+    CommentWithPayload,
+    #[error("Invalid payload: {0}")]
+    InvalidPayload(String),
+    #[error("Timeout must be between 1 and 3600 seconds")]
+    InvalidTimeout,
+    #[error("Invalid address of the recipient {0}")]
+    InvalidAddress(usize),
+    #[error("No recipients")]
+    NoRecipients,
+    #[error("Too many recipients")]
+    TooManyRecipients,
 }
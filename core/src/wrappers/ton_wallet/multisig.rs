@@ -0,0 +1,458 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use ed25519_dalek::PublicKey;
+use nekoton::contracts::abi;
+use nekoton::core::keystore::KeyStore;
+use nekoton::core::models::Expiration;
+use nekoton::core::ton_wallet::{ContractType, MultisigType, TonWallet};
+use nekoton::core::utils::make_labs_unsigned_message;
+use nekoton::crypto::UnsignedMessage;
+use nekoton::helpers::abi::FunctionExt;
+use nekoton::transport::Transport;
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use ton_abi::{Contract, Token, TokenValue, Uint};
+use ton_block::{AccountStuff, ExternalInboundMessageHeader, Message, MsgAddress, MsgAddressInt};
+
+use super::retry::{RetryPolicy, SendAttempt};
+use super::{
+    check_password, fetch_state, send_with_retries, SendError, SentMessage, SignData, Transfer,
+};
+use crate::wrappers::storage::signature;
+use crate::{GqlTransport, TonWalletSubscription};
+
+#[derive(Serialize)]
+pub struct Custodian {
+    /// Index of the custodian's bit in `confirmations_mask`
+    pub index: u8,
+    /// Hex encoded public key
+    pub public_key: String,
+}
+
+/// Transaction of the multisig, waiting for confirmations
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MultisigTransaction {
+    pub id: u64,
+    /// Bit `i` is set if the custodian with index `i` has confirmed the transaction
+    pub confirmations_mask: u32,
+    pub signs_required: u8,
+    pub signs_received: u8,
+    /// Hex encoded public key of the custodian, who submitted the transaction
+    pub creator: String,
+    pub index: u8,
+    pub dest: String,
+    pub value: u64,
+    pub send_flags: u16,
+    /// Base64 encoded BOC of the message body
+    pub payload: String,
+    pub bounce: bool,
+}
+
+/// Payload of the successful `submit_transaction` reply
+#[derive(Serialize)]
+pub struct SubmittedTransaction {
+    #[serde(flatten)]
+    pub sent: SentMessage,
+    /// Id of the created pending transaction, `null` if the transfer didn't need other confirmations
+    pub pending_id: Option<u64>,
+}
+
+/// Pending transaction to confirm on behalf of the custodian
+pub struct Confirmation {
+    pub custodian: PublicKey,
+    pub transaction_id: u64,
+    pub expiration: Expiration,
+}
+
+/// ABI of the wallet, if it's a multisig
+pub fn multisig_abi(ton_wallet: &TonWallet) -> Option<&'static Contract> {
+    match ton_wallet.contract_type() {
+        ContractType::Multisig(MultisigType::SafeMultisigWallet)
+        | ContractType::Multisig(MultisigType::SafeMultisigWallet24h) => {
+            Some(abi::safe_multisig_wallet())
+        }
+        ContractType::Multisig(MultisigType::SetcodeMultisigWallet)
+        | ContractType::Multisig(MultisigType::SurfWallet) => Some(abi::setcode_multisig_wallet()),
+        _ => None,
+    }
+}
+
+pub async fn get_custodians(
+    ton_wallet: &TonWallet,
+    transport: &dyn Transport,
+) -> Result<Vec<Custodian>, SendError> {
+    let abi = multisig_abi(ton_wallet).ok_or(SendError::NotMultisig)?;
+    let state = fetch_state(transport, ton_wallet.address()).await?;
+    custodians(abi, &state)
+}
+
+pub async fn get_pending_transactions(
+    ton_wallet: &TonWallet,
+    transport: &dyn Transport,
+) -> Result<Vec<MultisigTransaction>, SendError> {
+    let abi = multisig_abi(ton_wallet).ok_or(SendError::NotMultisig)?;
+    let state = fetch_state(transport, ton_wallet.address()).await?;
+    pending_transactions(abi, &state)
+}
+
+/// Submits the transfer, which stays pending until enough custodians confirm it,
+/// unless the wallet requires a single confirmation
+pub async fn submit_transaction<R>(
+    keystore: Arc<Mutex<KeyStore>>,
+    keystore_type: SignData,
+    transfer: Transfer,
+    ton_wallet: Arc<TonWalletSubscription>,
+    transport: Arc<GqlTransport>,
+    policy: RetryPolicy,
+    report: R,
+) -> Result<SubmittedTransaction, SendError>
+where
+    R: Fn(SendAttempt) + Sync,
+{
+    let abi = multisig_abi(&ton_wallet.inner).ok_or(SendError::NotMultisig)?;
+    let owner = *ton_wallet.inner.public_key();
+    check_signer(&keystore, &keystore_type, &owner).await?;
+    let confirmations = ton_wallet.confirmations.clone();
+    let mut ton_wallet = ton_wallet.inner.clone();
+    let transport = transport.inner.clone();
+
+    let state = fetch_state(transport.as_ref(), ton_wallet.address()).await?;
+    let known = pending_transactions(abi, &state)?
+        .into_iter()
+        .map(|transaction| transaction.id)
+        .collect::<HashSet<_>>();
+
+    let payload = transfer
+        .body()
+        .map(|body| body.into_cell())
+        .unwrap_or_default();
+    let input = vec![
+        Token::new("dest", TokenValue::Address(msg_address(&transfer.to))),
+        Token::new("value", uint(transfer.amount as u128, 128)),
+        Token::new("bounce", TokenValue::Bool(transfer.bounce)),
+        Token::new("allBalance", TokenValue::Bool(false)),
+        Token::new("payload", TokenValue::Cell(payload)),
+    ];
+    let mut message = prepare_call(
+        abi,
+        &ton_wallet,
+        &owner,
+        "submitTransaction",
+        input,
+        transfer.expiration,
+    )?;
+
+    let sent = send_with_retries(
+        &keystore,
+        &keystore_type,
+        &mut ton_wallet,
+        &confirmations,
+        &mut message,
+        &policy,
+        &report,
+    )
+    .await?;
+
+    let state = fetch_state(transport.as_ref(), ton_wallet.address()).await?;
+    let creator = hex::encode(owner.as_bytes());
+    let dest = transfer.to.to_string();
+    let pending_id = pending_transactions(abi, &state)?
+        .into_iter()
+        .find(|transaction| {
+            !known.contains(&transaction.id)
+                && transaction.creator == creator
+                && transaction.dest == dest
+                && transaction.value == transfer.amount
+        })
+        .map(|transaction| transaction.id);
+    Ok(SubmittedTransaction { sent, pending_id })
+}
+
+/// Confirms the pending transaction, signing the message with the custodian's key
+pub async fn confirm_transaction<R>(
+    keystore: Arc<Mutex<KeyStore>>,
+    keystore_type: SignData,
+    confirmation: Confirmation,
+    ton_wallet: Arc<TonWalletSubscription>,
+    transport: Arc<GqlTransport>,
+    policy: RetryPolicy,
+    report: R,
+) -> Result<SentMessage, SendError>
+where
+    R: Fn(SendAttempt) + Sync,
+{
+    let abi = multisig_abi(&ton_wallet.inner).ok_or(SendError::NotMultisig)?;
+    check_signer(&keystore, &keystore_type, &confirmation.custodian).await?;
+    let confirmations = ton_wallet.confirmations.clone();
+    let mut ton_wallet = ton_wallet.inner.clone();
+
+    let state = fetch_state(transport.inner.as_ref(), ton_wallet.address()).await?;
+    let public_key = hex::encode(confirmation.custodian.as_bytes());
+    let custodian = custodians(abi, &state)?
+        .into_iter()
+        .find(|custodian| custodian.public_key == public_key)
+        .ok_or(SendError::NotCustodian)?;
+    let transaction = pending_transactions(abi, &state)?
+        .into_iter()
+        .find(|transaction| transaction.id == confirmation.transaction_id)
+        .ok_or(SendError::TransactionNotFound)?;
+    if transaction.confirmations_mask & (1 << custodian.index) != 0 {
+        return Err(SendError::AlreadyConfirmed);
+    }
+
+    let input = vec![Token::new(
+        "transactionId",
+        uint(confirmation.transaction_id as u128, 64),
+    )];
+    let mut message = prepare_call(
+        abi,
+        &ton_wallet,
+        &confirmation.custodian,
+        "confirmTransaction",
+        input,
+        confirmation.expiration,
+    )?;
+
+    send_with_retries(
+        &keystore,
+        &keystore_type,
+        &mut ton_wallet,
+        &confirmations,
+        &mut message,
+        &policy,
+        &report,
+    )
+    .await
+}
+
+/// Remembers pending transactions of the multisig to report only the new ones
+pub struct PendingTracker {
+    abi: &'static Contract,
+    known: HashSet<u64>,
+    last_trans_lt: Option<u64>,
+}
+
+impl PendingTracker {
+    /// Returns `None` if the wallet isn't a multisig
+    pub fn new(ton_wallet: &TonWallet) -> Option<Self> {
+        multisig_abi(ton_wallet).map(|abi| Self {
+            abi,
+            known: HashSet::new(),
+            last_trans_lt: None,
+        })
+    }
+
+    /// Returns pending transactions, which appeared since the last poll.
+    /// Getters are run only if the wallet had new transactions
+    pub async fn poll(
+        &mut self,
+        ton_wallet: &TonWallet,
+        transport: &dyn Transport,
+    ) -> Result<Vec<MultisigTransaction>, SendError> {
+        let state = match fetch_state(transport, ton_wallet.address()).await {
+            Ok(state) => state,
+            Err(SendError::ContractDoesntExist) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        if self.last_trans_lt == Some(state.storage.last_trans_lt) {
+            return Ok(Vec::new());
+        }
+
+        let pending = match pending_transactions(self.abi, &state) {
+            Ok(pending) => pending,
+            // Not deployed yet
+            Err(SendError::GetterError(_)) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        self.last_trans_lt = Some(state.storage.last_trans_lt);
+        let known = pending.iter().map(|transaction| transaction.id).collect();
+        let found = pending
+            .into_iter()
+            .filter(|transaction| !self.known.contains(&transaction.id))
+            .collect();
+        self.known = known;
+        Ok(found)
+    }
+}
+
+/// Checks credentials and that they select the key of `signer`, which the message is signed on behalf of.
+/// The wallet would reject the message silently otherwise, leaving it to expire
+pub async fn check_signer(
+    keystore: &Mutex<KeyStore>,
+    keystore_type: &SignData,
+    signer: &PublicKey,
+) -> Result<(), SendError> {
+    let keystore = keystore.lock().await;
+    check_password(&keystore, keystore_type).await?;
+    let public_key = signature::public_key(&keystore, keystore_type)
+        .await
+        .map_err(|e| {
+            log::error!("Failed getting public key: {}", e);
+            SendError::WrongSigner
+        })?;
+    if public_key != *signer {
+        return Err(SendError::WrongSigner);
+    }
+    Ok(())
+}
+
+/// Builds external message, calling `function` of the wallet on behalf of `public_key`
+fn prepare_call(
+    abi: &'static Contract,
+    ton_wallet: &TonWallet,
+    public_key: &PublicKey,
+    function: &str,
+    input: Vec<Token>,
+    expiration: Expiration,
+) -> Result<Box<dyn UnsignedMessage>, SendError> {
+    let function = abi
+        .function(function)
+        .map_err(|e| SendError::GetterError(e.to_string()))?;
+    let message = Message::with_ext_in_header(ExternalInboundMessageHeader {
+        dst: ton_wallet.address().clone(),
+        ..Default::default()
+    });
+    Ok(make_labs_unsigned_message(
+        message,
+        expiration,
+        public_key,
+        Cow::Borrowed(function),
+        input,
+    )?)
+}
+
+/// Runs getter `name` of the contract locally, on the fetched state
+fn run_getter(abi: &Contract, state: &AccountStuff, name: &str) -> Result<Vec<Token>, SendError> {
+    let function = abi
+        .function(name)
+        .map_err(|e| SendError::GetterError(e.to_string()))?;
+    let output = function
+        .run_local(state.clone(), &[])
+        .map_err(|e| SendError::GetterError(e.to_string()))?;
+    output.tokens.ok_or_else(|| {
+        SendError::GetterError(format!(
+            "{} failed with exit code {}",
+            name, output.result_code
+        ))
+    })
+}
+
+fn custodians(abi: &Contract, state: &AccountStuff) -> Result<Vec<Custodian>, SendError> {
+    array(&run_getter(abi, state, "getCustodians")?, "custodians")?
+        .iter()
+        .map(|custodian| {
+            let custodian = tuple(custodian, "custodians")?;
+            Ok(Custodian {
+                index: number(custodian, "index")?,
+                public_key: public_key(custodian, "pubkey")?,
+            })
+        })
+        .collect()
+}
+
+fn pending_transactions(
+    abi: &Contract,
+    state: &AccountStuff,
+) -> Result<Vec<MultisigTransaction>, SendError> {
+    array(&run_getter(abi, state, "getTransactions")?, "transactions")?
+        .iter()
+        .map(|transaction| {
+            let transaction = tuple(transaction, "transactions")?;
+            Ok(MultisigTransaction {
+                id: number(transaction, "id")?,
+                confirmations_mask: number(transaction, "confirmationsMask")?,
+                signs_required: number(transaction, "signsRequired")?,
+                signs_received: number(transaction, "signsReceived")?,
+                creator: public_key(transaction, "creator")?,
+                index: number(transaction, "index")?,
+                dest: match field(transaction, "dest")? {
+                    TokenValue::Address(address) => address.to_string(),
+                    _ => return Err(unexpected("dest")),
+                },
+                value: number(transaction, "value")?,
+                send_flags: number(transaction, "sendFlags")?,
+                payload: match field(transaction, "payload")? {
+                    TokenValue::Cell(cell) => ton_types::serialize_toc(cell)
+                        .map(base64::encode)
+                        .map_err(|_| unexpected("payload"))?,
+                    _ => return Err(unexpected("payload")),
+                },
+                bounce: match field(transaction, "bounce")? {
+                    TokenValue::Bool(bounce) => *bounce,
+                    _ => return Err(unexpected("bounce")),
+                },
+            })
+        })
+        .collect()
+}
+
+fn msg_address(address: &MsgAddressInt) -> MsgAddress {
+    match address.clone() {
+        MsgAddressInt::AddrStd(address) => MsgAddress::AddrStd(address),
+        MsgAddressInt::AddrVar(address) => MsgAddress::AddrVar(address),
+    }
+}
+
+fn uint(number: u128, size: usize) -> TokenValue {
+    TokenValue::Uint(Uint {
+        number: BigUint::from(number),
+        size,
+    })
+}
+
+fn unexpected(name: &str) -> SendError {
+    SendError::GetterError(format!("Unexpected value of `{}`", name))
+}
+
+fn field<'a>(tokens: &'a [Token], name: &str) -> Result<&'a TokenValue, SendError> {
+    tokens
+        .iter()
+        .find(|token| token.name == name)
+        .map(|token| &token.value)
+        .ok_or_else(|| unexpected(name))
+}
+
+fn array<'a>(tokens: &'a [Token], name: &str) -> Result<&'a [TokenValue], SendError> {
+    match field(tokens, name)? {
+        TokenValue::Array(items) => Ok(items),
+        _ => Err(unexpected(name)),
+    }
+}
+
+fn tuple<'a>(value: &'a TokenValue, name: &str) -> Result<&'a [Token], SendError> {
+    match value {
+        TokenValue::Tuple(tokens) => Ok(tokens),
+        _ => Err(unexpected(name)),
+    }
+}
+
+fn number<T: TryFrom<u64>>(tokens: &[Token], name: &str) -> Result<T, SendError> {
+    let bytes = match field(tokens, name)? {
+        TokenValue::Uint(Uint { number, .. }) => number.to_bytes_le(),
+        _ => return Err(unexpected(name)),
+    };
+    if bytes.len() > 8 {
+        return Err(unexpected(name));
+    }
+    let mut number = [0; 8];
+    number[..bytes.len()].copy_from_slice(&bytes);
+    T::try_from(u64::from_le_bytes(number)).map_err(|_| unexpected(name))
+}
+
+/// Hex encoded `uint256` public key
+fn public_key(tokens: &[Token], name: &str) -> Result<String, SendError> {
+    let bytes = match field(tokens, name)? {
+        TokenValue::Uint(Uint { number, .. }) => number.to_bytes_be(),
+        _ => return Err(unexpected(name)),
+    };
+    if bytes.len() > 32 {
+        return Err(unexpected(name));
+    }
+    let mut public_key = [0; 32];
+    public_key[32 - bytes.len()..].copy_from_slice(&bytes);
+    Ok(hex::encode(public_key))
+}
@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+use super::SendError;
+use crate::ffi::ReplyErrorCode;
+
+/// Codes of the [`SendError`]s, which may be retried without paying twice:
+/// failures before the message is sent and expiration of the message, which wasn't included
+const RETRY_SAFE: [&str; 2] = ["TransportError", "Expired"];
+
+/// How many times and how often the message is re-signed and re-sent
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts including the first one
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled for every next one
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// How long to wait for the outcome of the message after it expires
+    pub confirmation_timeout_secs: u32,
+    /// Codes of the [`SendError`]s, which are worth another attempt, a subset of [`RETRY_SAFE`]
+    pub retryable: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            confirmation_timeout_secs: 60,
+            retryable: RETRY_SAFE.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), RetryPolicyError> {
+        if self.max_attempts == 0 {
+            return Err(RetryPolicyError::NoAttempts);
+        }
+        if self.confirmation_timeout_secs == 0 {
+            return Err(RetryPolicyError::NoTimeout);
+        }
+        if let Some(code) = self
+            .retryable
+            .iter()
+            .find(|code| !RETRY_SAFE.contains(&code.as_str()))
+        {
+            return Err(RetryPolicyError::UnsafeRetry(code.clone()));
+        }
+        Ok(())
+    }
+
+    /// Delay after the failed `attempt` (starting from 1), if another one should be made
+    pub fn backoff(&self, attempt: u32, error: &SendError) -> Option<Duration> {
+        let code = error.code();
+        if attempt >= self.max_attempts
+            || !RETRY_SAFE.contains(&code)
+            || !self.retryable.iter().any(|retryable| retryable == code)
+        {
+            return None;
+        }
+        let factor = 1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX);
+        let delay = self
+            .initial_backoff_ms
+            .saturating_mul(factor)
+            .min(self.max_backoff_ms);
+        Some(Duration::from_millis(delay))
+    }
+}
+
+/// Outcome of the single attempt, posted to the answer port as a progress reply
+#[derive(Serialize)]
+pub struct SendAttempt {
+    pub attempt: u32,
+    pub max_attempts: u32,
+    /// Error code, `null` if the attempt succeeded
+    pub code: Option<&'static str>,
+    pub message: Option<String>,
+    /// Delay before the next attempt, `null` if there won't be any
+    pub retry_in_ms: Option<u64>,
+}
+
+impl SendAttempt {
+    pub fn new(
+        attempt: u32,
+        policy: &RetryPolicy,
+        error: Option<&SendError>,
+        retry_in: Option<Duration>,
+    ) -> Self {
+        Self {
+            attempt,
+            max_attempts: policy.max_attempts,
+            code: error.map(|e| e.code()),
+            message: error.map(|e| e.to_string()),
+            retry_in_ms: retry_in.map(|delay| delay.as_millis() as u64),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RetryPolicyError {
+    #[error("At least one attempt must be allowed")]
+    NoAttempts,
+    #[error("Confirmation timeout must be positive")]
+    NoTimeout,
+    #[error("{0} can't be retried safely")]
+    UnsafeRetry(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 5000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let delays: Vec<_> = (1..=5)
+            .map(|attempt| policy().backoff(attempt, &SendError::Expired))
+            .map(|delay| delay.unwrap().as_millis())
+            .collect();
+        assert_eq!(delays, vec![1000, 2000, 4000, 5000, 5000]);
+    }
+
+    #[test]
+    fn backoff_doesnt_overflow() {
+        let delay = policy().backoff(9, &SendError::Expired);
+        assert_eq!(delay, Some(Duration::from_millis(5000)));
+
+        let policy = RetryPolicy {
+            max_attempts: u32::MAX,
+            max_backoff_ms: u64::MAX,
+            ..policy()
+        };
+        let delay = policy.backoff(100, &SendError::Expired);
+        assert_eq!(delay, Some(Duration::from_millis(u64::MAX)));
+    }
+
+    #[test]
+    fn no_backoff_after_last_attempt() {
+        assert!(policy().backoff(9, &SendError::Expired).is_some());
+        assert!(policy().backoff(10, &SendError::Expired).is_none());
+    }
+
+    #[test]
+    fn only_safe_errors_are_retried() {
+        let policy = policy();
+        let transport = SendError::TransportError("unreachable".to_string());
+        assert!(policy.backoff(1, &transport).is_some());
+        assert!(policy.backoff(1, &SendError::Expired).is_some());
+
+        for error in [
+            SendError::Timeout,
+            SendError::Unconfirmed("unreachable".to_string()),
+            SendError::SignError,
+            SendError::InvalidPassword,
+            SendError::InsufficientFunds,
+            SendError::NotCustodian,
+            SendError::WrongSigner,
+        ]
+        .iter()
+        {
+            assert!(policy.backoff(1, error).is_none(), "{} is retried", error);
+        }
+    }
+
+    #[test]
+    fn only_listed_errors_are_retried() {
+        let policy = RetryPolicy {
+            retryable: vec!["Expired".to_string()],
+            ..policy()
+        };
+        assert!(policy.backoff(1, &SendError::Expired).is_some());
+        let transport = SendError::TransportError("unreachable".to_string());
+        assert!(policy.backoff(1, &transport).is_none());
+    }
+
+    #[test]
+    fn validate_rejects_unsafe_retries() {
+        assert!(RetryPolicy::default().validate().is_ok());
+
+        for code in ["Timeout", "Unconfirmed", "Unknown"].iter() {
+            let policy = RetryPolicy {
+                retryable: vec![code.to_string()],
+                ..Default::default()
+            };
+            assert!(matches!(
+                policy.validate(),
+                Err(RetryPolicyError::UnsafeRetry(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn validate_rejects_empty_limits() {
+        let no_attempts = RetryPolicy {
+            max_attempts: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            no_attempts.validate(),
+            Err(RetryPolicyError::NoAttempts)
+        ));
+        let no_timeout = RetryPolicy {
+            confirmation_timeout_secs: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            no_timeout.validate(),
+            Err(RetryPolicyError::NoTimeout)
+        ));
+    }
+}